@@ -0,0 +1,280 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use sea_orm::{prelude::DateTimeUtc, EntityTrait};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::ext_trait::{ItemExt, StatusImage};
+use crate::feed_info::Entity as FeedInfo;
+use crate::fetch_log::{self, Entity as FetchLog, FetchStats};
+use crate::metrics::update_queue_depth;
+use crate::post_item::Entity as PostItem;
+use crate::schema::{Config, FeedConfig};
+use crate::setup::setup_connection;
+use crate::utility;
+use crate::{control::Feeds, render_status, Clients};
+
+/// 管理APIで共有する状態
+#[derive(Clone)]
+pub struct AdminState {
+    /// 再読み込みされた全体の設定
+    pub global: watch::Receiver<Arc<Config>>,
+    pub feeds: Feeds,
+    /// 設定されている場合は Authorization: Bearer で一致するリクエストのみ受け付ける
+    pub token: Option<String>,
+}
+
+/// 管理APIを起動します。
+/// トークンが設定されていない場合、ループバックアドレス以外では待ち受けません。
+pub async fn serve(addr: &str, state: AdminState) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/feeds", get(list_feeds))
+        .route("/feeds/{id}/fetch", post(fetch_feed))
+        .route("/feeds/{id}/pause", post(pause_feed))
+        .route("/feeds/{id}/resume", post(resume_feed))
+        .route("/feeds/{id}/preview", get(preview_feed))
+        .route("/feeds/{id}/fetch-log", get(list_fetch_log))
+        .route("/fetch-stats", get(fetch_stats))
+        .route("/queue", get(list_queue))
+        .route("/queue/{id}", delete(drop_queue))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state.clone());
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    if state.token.is_none() && !listener.local_addr()?.ip().is_loopback() {
+        return Err(anyhow::anyhow!(format!(
+            "ADMIN_TOKEN must be set to serve admin api on non-loopback address: {}",
+            addr
+        )));
+    }
+    info!(addr, "admin api listening");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+struct AdminError(StatusCode, String);
+
+impl<E: Into<anyhow::Error>> From<E> for AdminError {
+    fn from(e: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e.into()))
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+/// トークンが設定されている場合は Bearer トークンを確認します。
+async fn authorize(
+    State(state): State<AdminState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if let Some(token) = &state.token {
+        let bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if bearer != Some(token.as_str()) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+fn not_found(id: &str) -> AdminError {
+    AdminError(StatusCode::NOT_FOUND, format!("not found: {}", id))
+}
+
+#[derive(Serialize)]
+struct FeedResponse {
+    id: String,
+    url: String,
+    title: Option<String>,
+    paused: bool,
+    last_fetch: Option<DateTimeUtc>,
+    next_fetch: Option<DateTimeUtc>,
+    next_fetch_reason: Option<String>,
+    failures: i32,
+    last_error: Option<String>,
+    dead: bool,
+}
+
+async fn list_feeds(
+    State(state): State<AdminState>,
+) -> Result<Json<Vec<FeedResponse>>, AdminError> {
+    let db = setup_connection().await?;
+    let infos = FeedInfo::find().all(&db).await?;
+    db.close().await?;
+    let feeds = state.feeds.lock().await;
+    let mut res = feeds
+        .iter()
+        .map(|(id, running)| {
+            let info = infos.iter().find(|i| i.source == *id);
+            FeedResponse {
+                id: id.clone(),
+                url: running.config.url.clone(),
+                title: info.and_then(|i| i.title.clone()),
+                paused: running.control.is_paused(),
+                last_fetch: info.map(|i| i.last_fetch),
+                next_fetch: info.map(|i| i.next_fetch),
+                next_fetch_reason: info.and_then(|i| i.next_fetch_reason.clone()),
+                failures: info.map(|i| i.failures).unwrap_or_default(),
+                last_error: info.and_then(|i| i.last_error.clone()),
+                dead: info.is_some_and(|i| i.dead),
+            }
+        })
+        .collect::<Vec<_>>();
+    res.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(Json(res))
+}
+
+async fn fetch_feed(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let feeds = state.feeds.lock().await;
+    let running = feeds.get(&id).ok_or_else(|| not_found(&id))?;
+    running.control.fetch_now();
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn pause_feed(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let feeds = state.feeds.lock().await;
+    let running = feeds.get(&id).ok_or_else(|| not_found(&id))?;
+    running.control.pause();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume_feed(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let feeds = state.feeds.lock().await;
+    let running = feeds.get(&id).ok_or_else(|| not_found(&id))?;
+    running.control.resume();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct PreviewResponse {
+    destination: String,
+    spoiler_text: Option<String>,
+    text: String,
+    image: Option<String>,
+}
+
+/// 最新の記事を投稿先ごとの設定で作成した投稿内容を返します。投稿はしません。
+async fn preview_feed(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<PreviewResponse>>, AdminError> {
+    let config: FeedConfig = {
+        let feeds = state.feeds.lock().await;
+        let running = feeds.get(&id).ok_or_else(|| not_found(&id))?;
+        running.config.clone()
+    };
+    let feed = utility::fetch_feed(&config.url).await?;
+    let now = Utc::now();
+    let entry = feed
+        .entries
+        .iter()
+        .filter(|e| e.title.is_some() && !e.links.is_empty())
+        .max_by_key(|e| *e.pub_date_utc_or(&now))
+        .ok_or_else(|| not_found(&id))?;
+    let feed_title = feed.title.as_ref().map(|t| t.content.clone());
+
+    let global = state.global.borrow().clone();
+    let mut clients = Clients::default();
+    let mut res = vec![];
+    for dest in config.destinations() {
+        let (sns, limit, _) = clients.get(&global, &config, &dest).await;
+        let status =
+            render_status(&sns, &limit, &global, &config, &dest, &feed_title, entry).await?;
+        res.push(PreviewResponse {
+            destination: dest.id.clone(),
+            spoiler_text: status.spoiler_text,
+            text: status.text,
+            image: status.image.map(|StatusImage { url, .. }| url),
+        });
+    }
+    Ok(Json(res))
+}
+
+/// フィードの直近の取得履歴を新しい順に返します。
+async fn list_fetch_log(Path(id): Path<String>) -> Result<Json<Vec<fetch_log::Model>>, AdminError> {
+    let db = setup_connection().await?;
+    let logs = FetchLog::find_recent(&db, &id, 100).await?;
+    db.close().await?;
+    Ok(Json(logs))
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    days: Option<u32>,
+}
+
+/// フィードごとの取得履歴の集計を返します。既定では直近7日間を集計します。
+async fn fetch_stats(Query(query): Query<StatsQuery>) -> Result<Json<Vec<FetchStats>>, AdminError> {
+    let since = Utc::now() - Duration::days(query.days.unwrap_or(7).into());
+    let db = setup_connection().await?;
+    let stats = FetchLog::stats(&db, since).await?;
+    db.close().await?;
+    Ok(Json(stats))
+}
+
+#[derive(Serialize)]
+struct QueueResponse {
+    id: i32,
+    source: String,
+    title: String,
+    link: String,
+    pub_date: DateTimeUtc,
+    attempts: i32,
+}
+
+async fn list_queue() -> Result<Json<Vec<QueueResponse>>, AdminError> {
+    let db = setup_connection().await?;
+    let items = PostItem::find_queued().all(&db).await?;
+    db.close().await?;
+    Ok(Json(
+        items
+            .into_iter()
+            .map(|i| QueueResponse {
+                id: i.id,
+                source: i.source,
+                title: i.title,
+                link: i.link,
+                pub_date: i.pub_date,
+                attempts: i.attempts,
+            })
+            .collect(),
+    ))
+}
+
+async fn drop_queue(Path(id): Path<i32>) -> Result<StatusCode, AdminError> {
+    let db = setup_connection().await?;
+    let dropped = PostItem::drop_queued(&db, id).await?;
+    update_queue_depth(&db).await;
+    db.close().await?;
+    if dropped {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(not_found(&id.to_string()))
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::HashSet, env, fs::File, path::PathBuf};
+
+use anyhow::{anyhow, bail};
+use chrono::{Duration, Utc};
+use clap::{Parser, Subcommand};
+use feed_rs::model::Entry;
+use sea_orm::*;
+
+use crate::constants::IS_DRY_RUN_ENV;
+use crate::ext_trait::{ItemExt, StatusImage};
+use crate::feed_info::Entity as FeedInfo;
+use crate::fetch_log::Entity as FetchLog;
+use crate::legacy::{strip_nulls, LegacyConfig};
+use crate::post_destination::Entity as PostDestination;
+use crate::post_item::{self, Entity as PostItem};
+use crate::schema::{Config, FeedConfig};
+use crate::setup::{load_config, setup_connection, setup_tables};
+use crate::utility::{fetch_feed, normalize_link};
+use crate::{find_new_entries, process_post, render_status, Clients, PostKind};
+
+/// RSSやAtomのフィードをMastodonなどに投稿します。
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// フィードを監視して新着を投稿します (既定)
+    Run,
+    /// 設定ファイルを検証します
+    ValidateConfig {
+        /// トークンの確認を省略します
+        #[arg(long)]
+        skip_auth: bool,
+    },
+    /// 次に投稿される内容を表示します。投稿はしません
+    Preview {
+        feed_id: String,
+        /// 新着がなくても最新の記事を表示します
+        #[arg(long)]
+        latest: bool,
+    },
+    /// データベースのテーブルを作成します
+    Migrate,
+    /// フィードの取得状況を表示します
+    ListFeeds,
+    /// フィードごとの取得履歴を集計し、新着を検出するまでの時間を表示します
+    FetchStats {
+        /// 集計する日数
+        #[arg(long, default_value_t = 7)]
+        days: u32,
+    },
+    /// 指定した記事を投稿します
+    Post { feed_id: String, link: String },
+    /// 旧バージョンの設定ファイル (sources.yml) を現在の形式に変換して出力します
+    ConvertConfig { path: PathBuf },
+}
+
+/// run 以外のサブコマンドを実行します。
+pub async fn execute(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run => bail!("run is handled by main"),
+        Command::ValidateConfig { skip_auth } => validate_config(skip_auth).await,
+        Command::Preview { feed_id, latest } => preview(&feed_id, latest).await,
+        Command::Migrate => {
+            setup_tables().await?;
+            println!("migrated");
+            Ok(())
+        }
+        Command::ListFeeds => list_feeds().await,
+        Command::FetchStats { days } => fetch_stats(days).await,
+        Command::Post { feed_id, link } => post(&feed_id, &link).await,
+        Command::ConvertConfig { path } => convert_config(&path),
+    }
+}
+
+fn config() -> anyhow::Result<Config> {
+    load_config().map_err(|e| anyhow!(e.to_string()))
+}
+
+fn find_feed<'a>(config: &'a Config, id: &str) -> anyhow::Result<&'a FeedConfig> {
+    config
+        .feeds
+        .iter()
+        .find(|f| f.id == id)
+        .ok_or_else(|| anyhow!("feed not found: {}", id))
+}
+
+async fn validate_config(skip_auth: bool) -> anyhow::Result<()> {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            bail!("invalid config");
+        }
+    };
+    let mut errors = vec![];
+    if !skip_auth {
+        let mut clients = Clients::default();
+        let mut checked = HashSet::new();
+        for feed in &config.feeds {
+            for dest in feed.destinations() {
+                let path = format!("{} ({})", feed.id, dest.id);
+                let server = config.server(feed, &dest).to_string();
+                if !checked.insert((server, dest.token.clone())) {
+                    continue;
+                }
+                let (_, _, client) = clients.get(&config, feed, &dest).await;
+                match client.verify_account_credentials().await {
+                    Ok(res) => println!("{}: authenticated as @{}", path, res.json().acct),
+                    Err(e) => errors.push(format!("{}: failed to authenticate: {}", path, e)),
+                }
+            }
+        }
+    }
+    if errors.is_empty() {
+        println!("{} feeds ok", config.feeds.len());
+        return Ok(());
+    }
+    for error in &errors {
+        println!("{}", error);
+    }
+    bail!("{} errors found", errors.len())
+}
+
+async fn preview(feed_id: &str, latest: bool) -> anyhow::Result<()> {
+    let global = config()?;
+    let config = find_feed(&global, feed_id)?;
+    let feed = fetch_feed(&config.url).await?;
+    let entries = feed
+        .entries
+        .iter()
+        .filter(|e| e.title.is_some() && !e.links.is_empty())
+        .collect::<Vec<_>>();
+    let entries = if latest {
+        let now = Utc::now();
+        entries
+            .into_iter()
+            .max_by_key(|e| *e.pub_date_utc_or(&now))
+            .into_iter()
+            .collect()
+    } else {
+        let db = setup_connection().await?;
+        let recent = PostItem::find_recent(&db, &config.id).await?;
+        db.close().await?;
+        if recent.is_empty() {
+            println!("first fetch: entries are registered without posting");
+            return Ok(());
+        }
+        find_new_entries(&recent, entries)
+    };
+    if entries.is_empty() {
+        println!("no new entries");
+        return Ok(());
+    }
+
+    let feed_title = feed.title.as_ref().map(|t| t.content.clone());
+    let mut clients = Clients::default();
+    for entry in entries {
+        for dest in config.destinations() {
+            let (sns, limit, _) = clients.get(&global, config, &dest).await;
+            let status =
+                render_status(&sns, &limit, &global, config, &dest, &feed_title, entry).await?;
+            println!("--- {} ({})", entry.normalized_link(), dest.id);
+            if let Some(spoiler_text) = &status.spoiler_text {
+                println!("[CW] {}", spoiler_text);
+            }
+            println!("{}", status.text);
+            if let Some(StatusImage { url, .. }) = &status.image {
+                println!("[image] {}", url);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn list_feeds() -> anyhow::Result<()> {
+    let db = setup_connection().await?;
+    let infos = FeedInfo::find()
+        .order_by_asc(crate::feed_info::Column::Source)
+        .all(&db)
+        .await?;
+    println!("id\ttitle\tlast_fetch\tnext_fetch\tqueued\tfailures\treason");
+    for info in infos {
+        let queued = PostItem::find_queued()
+            .filter(post_item::Column::Source.eq(&info.source))
+            .count(&db)
+            .await?;
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}{}\t{}",
+            info.source,
+            info.title.unwrap_or_default(),
+            info.last_fetch.to_rfc3339(),
+            info.next_fetch.to_rfc3339(),
+            queued,
+            info.failures,
+            if info.dead { " (dead)" } else { "" },
+            info.next_fetch_reason.unwrap_or_default()
+        );
+    }
+    db.close().await?;
+    Ok(())
+}
+
+/// 時間は秒で表示します。
+async fn fetch_stats(days: u32) -> anyhow::Result<()> {
+    let db = setup_connection().await?;
+    let stats = FetchLog::stats(&db, Utc::now() - Duration::days(days.into())).await?;
+    db.close().await?;
+    let seconds = |s: Option<i64>| s.map(|s| s.to_string()).unwrap_or_default();
+    println!("id\tfetches\terrors\tnew_entries\tavg_latency\tmax_latency\tavg_interval");
+    for s in stats {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            s.source,
+            s.fetches,
+            s.errors,
+            s.new_entries,
+            seconds(s.avg_latency),
+            seconds(s.max_latency),
+            seconds(s.avg_interval)
+        );
+    }
+    Ok(())
+}
+
+async fn post(feed_id: &str, link: &str) -> anyhow::Result<()> {
+    let global = config()?;
+    let config = find_feed(&global, feed_id)?;
+    let is_dry_run = env::var(IS_DRY_RUN_ENV).is_ok();
+    let link = normalize_link(link);
+    let feed = fetch_feed(&config.url).await?;
+    let entry: &Entry = feed
+        .entries
+        .iter()
+        .filter(|e| e.title.is_some() && !e.links.is_empty())
+        .find(|e| e.normalized_link() == link)
+        .ok_or_else(|| anyhow!("entry not found in feed: {}", link))?;
+
+    let db = setup_connection().await?;
+    let posted = PostItem::find()
+        .filter(post_item::Column::Source.eq(&config.id))
+        .filter(post_item::Column::Link.eq(&link))
+        .filter(post_item::Column::PostId.is_not_null())
+        .one(&db)
+        .await?;
+    if posted.is_some() {
+        bail!("already posted: {}", link);
+    }
+    let item = PostItem::enqueue(&db, &config.id, entry).await?;
+    let mut clients = Clients::default();
+    process_post(
+        &db,
+        &mut clients,
+        &global,
+        config,
+        item.id,
+        PostKind::New(entry.clone()),
+        &is_dry_run,
+    )
+    .await;
+    let posted = PostItem::find_by_id(item.id).one(&db).await?;
+    if let Some(post_id) = posted.and_then(|i| i.post_id) {
+        db.close().await?;
+        println!("posted: {} {}", link, post_id);
+        return Ok(());
+    }
+    // 起動中の投稿処理が再試行しないよう、どこにも投稿できなければ登録ごと削除し、
+    // 一部の投稿先に投稿できた場合は投稿結果を残してキューだけ取り消す
+    if PostDestination::find_by_item(&db, item.id)
+        .await?
+        .is_empty()
+    {
+        PostItem::delete_by_id(item.id).exec(&db).await?;
+    } else {
+        PostItem::drop_queued(&db, item.id).await?;
+    }
+    db.close().await?;
+    bail!("failed to post: {}", link)
+}
+
+/// 変換した設定は標準出力に、変換できなかった項目は標準エラー出力に出力します。
+fn convert_config(path: &PathBuf) -> anyhow::Result<()> {
+    let legacy: LegacyConfig = serde_yaml::from_reader(File::open(path)?)?;
+    let (config, unmapped) = legacy.convert();
+    print!(
+        "{}",
+        serde_yaml::to_string(&strip_nulls(serde_yaml::to_value(&config)?))?
+    );
+    for (key, ids) in &unmapped {
+        let mut sources = ids.iter().take(5).cloned().collect::<Vec<_>>();
+        if ids.len() > sources.len() {
+            sources.push("...".to_string());
+        }
+        eprintln!(
+            "{}: not converted ({} sources: {})",
+            key,
+            ids.len(),
+            sources.join(", ")
+        );
+    }
+    for error in config.validate() {
+        eprintln!("{}", error);
+    }
+    Ok(())
+}
@@ -1,56 +1,95 @@
 use chrono::Duration;
 use once_cell::sync::Lazy;
-use std::env;
+use std::{env, fmt::Display, str::FromStr};
+use tracing::warn;
 
 const DATABASE_URL_ENV: &str = "DATABASE_URL";
 pub const FEED_CONFIG_PATH_ENV: &str = "FEED_CONFIG_PATH";
 pub const IS_DRY_RUN_ENV: &str = "IS_DRY_RUN";
+/// destinations を指定しないフィードの投稿先の識別子
+pub const DEFAULT_DESTINATION: &str = "default";
+/// 取り下げられた記事の投稿に返信する文章の既定値
+pub const DEFAULT_RETRACT_NOTE: &str = "この記事は取り下げられました。";
+/// 添付する画像の最大サイズの既定値 (Mastodonの画像の上限)
+pub const DEFAULT_MEDIA_MAX_SIZE: u64 = 16 * 1024 * 1024;
+/// 添付する画像の Content-Type の既定値
+pub const DEFAULT_MEDIA_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/gif", "image/webp"];
 
-pub static QUEUE_INTERVAL: Lazy<Duration> = Lazy::new(|| {
-    Duration::seconds(
-        env::var("QUEUE_INTERVAL")
-            .unwrap_or("1".to_string())
-            .parse()
-            .unwrap(),
-    )
-});
-pub static POST_INTERVAL: Lazy<Duration> = Lazy::new(|| {
-    Duration::seconds(
-        env::var("POST_INTERVAL")
-            .unwrap_or("5".to_string())
-            .parse()
-            .unwrap(),
-    )
-});
-pub static MAX_QUEUE: Lazy<usize> = Lazy::new(|| {
-    env::var("MAX_QUEUE")
-        .unwrap_or("1000".to_string())
-        .parse()
-        .unwrap()
-});
-pub static MIN_WAIT: Lazy<Duration> = Lazy::new(|| {
-    Duration::minutes(
-        env::var("MIN_WAIT")
-            .unwrap_or("5".to_string())
-            .parse()
-            .unwrap(),
-    )
-});
-pub static MAX_WAIT: Lazy<Duration> = Lazy::new(|| {
-    Duration::minutes(
-        env::var("MAX_WAIT")
-            .unwrap_or("60".to_string())
-            .parse()
-            .unwrap(),
-    )
-});
-pub static CONFIG_INTERVAL: Lazy<Duration> = Lazy::new(|| {
-    Duration::seconds(
-        env::var("CONFIG_INTERVAL")
-            .unwrap_or("60".to_string())
-            .parse()
-            .unwrap(),
-    )
+/// 新着を投稿キューに追加する間隔 (秒) の既定値。設定ファイルの queue_interval が優先される
+pub static QUEUE_INTERVAL: Lazy<Duration> =
+    Lazy::new(|| Duration::seconds(env_at_least("QUEUE_INTERVAL", 1, 0)));
+/// 投稿する間隔 (秒) の既定値。設定ファイルの post_interval が優先される
+pub static POST_INTERVAL: Lazy<Duration> =
+    Lazy::new(|| Duration::seconds(env_at_least("POST_INTERVAL", 5, 0)));
+pub static MAX_QUEUE: Lazy<usize> = Lazy::new(|| env_or("MAX_QUEUE", 1000));
+pub static MAX_POST_ATTEMPTS: Lazy<i32> = Lazy::new(|| env_or("MAX_POST_ATTEMPTS", 3));
+/// 投稿に失敗したキューを再試行する間隔 (秒)
+pub static RETRY_INTERVAL: Lazy<Duration> =
+    Lazy::new(|| Duration::seconds(env_at_least("RETRY_INTERVAL", 300, 1)));
+pub static RECENT_POST_LIMIT: Lazy<u64> = Lazy::new(|| env_or("RECENT_POST_LIMIT", 1000));
+/// フィードを取得する最短の間隔 (分) の既定値。設定ファイルの min_wait が優先される
+pub static MIN_WAIT: Lazy<Duration> = Lazy::new(|| Duration::minutes(env_or("MIN_WAIT", 5)));
+/// フィードを取得する最長の間隔 (分) の既定値。設定ファイルの max_wait が優先される
+pub static MAX_WAIT: Lazy<Duration> = Lazy::new(|| Duration::minutes(env_or("MAX_WAIT", 60)));
+/// 取得に失敗し続けた場合の待機時間の上限 (分) の既定値。設定ファイルの max_backoff が優先される
+pub static MAX_BACKOFF: Lazy<Duration> =
+    Lazy::new(|| Duration::minutes(env_or("MAX_BACKOFF", 1440)));
+/// フィードを停止扱いにするまで失敗し続けた日数の既定値。設定ファイルの dead_after が優先される
+pub static DEAD_AFTER: Lazy<Duration> = Lazy::new(|| Duration::days(env_or("DEAD_AFTER", 7)));
+/// SNSの判定に失敗した場合にMastodonとして扱い、再判定するまでの時間 (分)
+pub static SNS_DETECT_TTL: Lazy<Duration> =
+    Lazy::new(|| Duration::minutes(env_or("SNS_DETECT_TTL", 60)));
+/// 取り下げを判定するためにリンクを確認する間隔 (フィードで連続して見つからなかった取得回数)
+pub static RETRACT_CHECK_MISSES: Lazy<i32> =
+    Lazy::new(|| env_at_least("RETRACT_CHECK_MISSES", 3, 1));
+/// 非同期で処理される画像の処理が終わるのを待つ時間 (秒)。過ぎた場合は画像を添付しない
+pub static MEDIA_PROCESS_TIMEOUT: Lazy<Duration> =
+    Lazy::new(|| Duration::seconds(env_at_least("MEDIA_PROCESS_TIMEOUT", 30, 0)));
+/// 終了の合図を受けてから投稿中の記事を待つ時間 (docker stop の猶予より短くする)
+pub static SHUTDOWN_TIMEOUT: Lazy<Duration> =
+    Lazy::new(|| Duration::seconds(env_at_least("SHUTDOWN_TIMEOUT", 8, 0)));
+/// 設定ファイルを再読み込みする間隔 (秒) の既定値。設定ファイルの config_interval が優先される
+pub static CONFIG_INTERVAL: Lazy<Duration> =
+    Lazy::new(|| Duration::seconds(env_at_least("CONFIG_INTERVAL", 60, 1)));
+/// 取得履歴を残す日数の既定値。設定ファイルの fetch_log_days が優先される
+pub static FETCH_LOG_DAYS: Lazy<Duration> =
+    Lazy::new(|| Duration::days(env_or("FETCH_LOG_DAYS", 30)));
+/// 管理APIの待ち受けアドレス (未設定の場合は起動しない)
+pub static ADMIN_ADDR: Lazy<Option<String>> = Lazy::new(|| env::var("ADMIN_ADDR").ok());
+/// 管理APIの Bearer トークン (未設定の場合はループバックアドレスでのみ待ち受ける)
+pub static ADMIN_TOKEN: Lazy<Option<String>> =
+    Lazy::new(|| env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()));
+/// Prometheus 向けのメトリクスの待ち受けアドレス (未設定の場合は起動しない)
+pub static METRICS_ADDR: Lazy<Option<String>> = Lazy::new(|| env::var("METRICS_ADDR").ok());
+/// ログの出力レベル (tracing_subscriber::EnvFilter の書式)
+pub static LOG_LEVEL: Lazy<String> =
+    Lazy::new(|| env::var("LOG_LEVEL").unwrap_or("info,sqlx=warn".to_string()));
+/// ログの書式 (json, pretty, 省略時は1行のテキスト)
+pub static LOG_FORMAT: Lazy<String> =
+    Lazy::new(|| env::var("LOG_FORMAT").unwrap_or("text".to_string()));
+
+/// 環境変数を読み込みます。未設定または解釈できない場合は既定値を使用します。
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    let Ok(value) = env::var(name) else {
+        return default;
+    };
+    value.parse().unwrap_or_else(|_| {
+        warn!(name, value, "invalid environment variable, using default");
+        default
+    })
+}
+
+/// 環境変数を読み込みます。最小値より小さい場合は最小値を使用します。
+/// 負の時間や0秒の間隔で待機や tokio::time::interval が panic しないようにします。
+fn env_at_least<T: FromStr + PartialOrd + Display>(name: &str, default: T, min: T) -> T {
+    let value = env_or(name, default);
+    if value < min {
+        warn!(name, %value, %min, "environment variable is too small, using minimum");
+        return min;
+    }
+    value
+}
+
+pub static DATABASE_URL: Lazy<String> = Lazy::new(|| {
+    env::var(DATABASE_URL_ENV).unwrap_or_else(|_| panic!("{} must be set", DATABASE_URL_ENV))
 });
-pub static DATABASE_URL: Lazy<String> =
-    Lazy::new(|| env::var(DATABASE_URL_ENV).expect(&format!("{} must be set", DATABASE_URL_ENV)));
@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use chrono::Duration;
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+use tracing::info;
+
+use crate::schema::{FeedConfig, FeedSchedule};
+use crate::utility::sleep;
+
+/// 実行中のフィードを外部から操作するためのハンドル
+#[derive(Debug, Default)]
+pub struct FeedControl {
+    paused: AtomicBool,
+    wake: Notify,
+}
+
+impl FeedControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// 次の取得から停止します。
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.wake.notify_one();
+    }
+
+    /// 待機中であればすぐに取得します。
+    pub fn fetch_now(&self) {
+        self.wake.notify_one();
+    }
+
+    /// 停止中であれば再開されるまで待機します。
+    pub async fn wait_resume(&self, label: &str) {
+        if self.is_paused() {
+            info!(label, "paused");
+        }
+        while self.is_paused() {
+            self.wake.notified().await;
+        }
+    }
+
+    /// 指定時間待機します。fetch_now または resume が呼ばれた場合はすぐに戻ります。
+    pub async fn sleep(&self, duration: &Duration, label: &str) {
+        tokio::select! {
+            _ = sleep(duration, label) => {}
+            _ = self.wake.notified() => info!(label, "wake"),
+        }
+    }
+}
+
+/// 実行中のフィード
+pub struct RunningFeed {
+    pub config: FeedConfig,
+    /// 全体の設定を反映した取得の間隔
+    pub schedule: FeedSchedule,
+    pub control: Arc<FeedControl>,
+    pub handle: JoinHandle<()>,
+}
+
+/// 設定の再読み込みと管理APIで共有する実行中のフィードの一覧
+pub type Feeds = Arc<Mutex<HashMap<String, RunningFeed>>>;
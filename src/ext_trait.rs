@@ -1,15 +1,27 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, iter::once};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use encoding_rs::*;
+use feed_rs::model::Person;
+use megalodon::SNS;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use reqwest::Url;
 use sxd_xpath::{evaluate_xpath, Value::Nodeset};
+use tracing::warn;
 
-use crate::TagConfig;
+use crate::{
+    template::{StatusContext, StatusTemplate},
+    utility::normalize_link,
+    TagConfig,
+};
 
 static TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[^\w]+").unwrap()); // 単語文字以外の文字にマッチする正規表現
+static MISSKEY_TAG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r##"[\s.,!?'"#:/\[\]【】()「」（）<>]+"##).unwrap()); // Misskeyでハッシュタグの区切りになる文字にマッチする正規表現
+static AUTHOR_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\((.+)\)\s*$").unwrap()); // RSSのauthorの括弧内の名前にマッチする正規表現
+static URL_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://\S+").unwrap()); // URLにマッチする正規表現
 static COMBINE_TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"#\s(\w)").unwrap()); // #と単語文字の間にスペースがある場合にマッチする正規表現
 
 pub trait ISO8601 {
@@ -50,85 +62,82 @@ impl ISO8601 for Duration {
     }
 }
 
-pub trait StringBuilderExt {
-    fn append_with_line<T: string_builder::ToBytes>(&mut self, buf: T) -> &mut Self;
-    fn append_line(&mut self) -> &mut Self;
-}
-
-impl StringBuilderExt for string_builder::Builder {
-    fn append_with_line<T: string_builder::ToBytes>(&mut self, buf: T) -> &mut Self {
-        self.append(buf);
-        self.append("\n");
-        self
-    }
-    fn append_line(&mut self) -> &mut Self {
-        self.append("\n");
-        self
-    }
-}
-
 #[async_trait]
 pub trait ItemExt {
     fn pub_date_utc(&self) -> Option<&DateTime<Utc>>;
     fn pub_date_utc_or<'a>(&'a self, or: &'a DateTime<Utc>) -> &'a DateTime<Utc>;
-    async fn to_status(&self, id: String, config: &TagConfig) -> anyhow::Result<String>;
+    fn normalized_link(&self) -> String;
+    async fn to_status(
+        &self,
+        id: String,
+        feed_title: Option<String>,
+        config: &TagConfig,
+        sns: &SNS,
+        template: StatusTemplate<'_>,
+        limit: &StatusLimit,
+    ) -> anyhow::Result<StatusContent>;
 }
 
 #[async_trait]
 impl ItemExt for feed_rs::model::Entry {
     fn pub_date_utc(&self) -> Option<&DateTime<Utc>> {
-        if let Some(p) = &self.published {
-            Some(&p)
-        } else if let Some(u) = &self.updated {
-            Some(&u)
-        } else {
-            None
-        }
+        self.published.as_ref().or(self.updated.as_ref())
     }
 
     fn pub_date_utc_or<'a>(&'a self, or: &'a DateTime<Utc>) -> &'a DateTime<Utc> {
-        if let Some(p) = self.pub_date_utc() {
-            &p
-        } else {
-            or
-        }
+        self.pub_date_utc().unwrap_or(or)
     }
 
-    async fn to_status(&self, id: String, config: &TagConfig) -> anyhow::Result<String> {
-        let mut b = string_builder::Builder::default();
-        let mut title = None;
-        if let Some(t) = &self.title {
-            let head = COMBINE_TAG_RE
-                .replace_all(t.content.as_str(), "#$1")
-                .to_string();
-            b.append_with_line(head);
-            title = Some(&t.content);
-        }
-        for link in &self.links {
-            b.append_with_line(link.href.as_str());
-        }
+    fn normalized_link(&self) -> String {
+        self.links
+            .first()
+            .map(|l| normalize_link(&l.href))
+            .unwrap_or_default()
+    }
+
+    async fn to_status(
+        &self,
+        id: String,
+        feed_title: Option<String>,
+        config: &TagConfig,
+        sns: &SNS,
+        template: StatusTemplate<'_>,
+        limit: &StatusLimit,
+    ) -> anyhow::Result<StatusContent> {
+        let title = self.title.as_ref().map(|t| &t.content);
         // すごいメモリ無駄にしている気がする…
-        let mut tags = std::iter::once(id)
-            .chain(
-                self.categories
-                    .iter()
-                    .map(|c| c.label.clone().unwrap_or(c.term.clone())),
-            )
-            .collect::<Vec<String>>();
-        tags.extend(config.always.clone());
+        let mut tags = once((id.clone(), TagPriority::Fixed))
+            .chain(self.categories.iter().map(|c| {
+                (
+                    c.label.clone().unwrap_or(c.term.clone()),
+                    TagPriority::Category,
+                )
+            }))
+            .collect::<Vec<_>>();
+        tags.extend(
+            config
+                .always
+                .iter()
+                .map(|t| (t.clone(), TagPriority::Fixed)),
+        );
 
+        let mut image = None;
         for link in &self.links {
             let response = reqwest::get(&link.href).await?;
             let contents = decode_text(response).await?;
             let package = sxd_html::parse_html(&contents);
             let doc = package.as_document();
 
+            if image.is_none() {
+                image = find_page_image(&doc, &link.href);
+            }
+
             // keywordsがfalseに設定されている場合はメタキーワードを抽出しない
             if config.keywords.unwrap_or(true) {
                 if let Nodeset(nodes) = evaluate_xpath(&doc, "//meta[@name='keywords']/@content")? {
                     for node in nodes {
                         for keyword in node.string_value().split(',') {
-                            tags.push(keyword.trim().to_string());
+                            tags.push((keyword.trim().to_string(), TagPriority::Page));
                         }
                     }
                 }
@@ -136,12 +145,17 @@ impl ItemExt for feed_rs::model::Entry {
 
             // xpathがない場合は無視
             let Some(xpath) = &config.xpath else { continue };
-            let Ok(Nodeset(nodes)) = evaluate_xpath(&doc, xpath) else {
-                // TODO: Sentryに送る
-                continue;
+            // 構文は設定の読み込み時に検証済み
+            let nodes = match evaluate_xpath(&doc, xpath) {
+                Ok(Nodeset(nodes)) => nodes,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!(xpath, link = %link.href, error = &e as &dyn std::error::Error, "failed to evaluate xpath");
+                    continue;
+                }
             };
             for node in nodes {
-                tags.push(node.string_value().trim().to_string());
+                tags.push((node.string_value().trim().to_string(), TagPriority::Page));
             }
         }
 
@@ -149,16 +163,18 @@ impl ItemExt for feed_rs::model::Entry {
             let replace = config
                 .replace
                 .iter()
+                // 正規表現は設定の読み込み時に検証済み
                 .filter_map(|i| Regex::new(i).ok())
                 .collect::<Vec<Regex>>();
             tags = tags
                 .into_iter()
-                .map(|t| {
-                    replace
+                .map(|(t, p)| {
+                    let t = replace
                         .iter()
-                        .fold(t, |t, r| r.replace_all(&t, "").to_string())
+                        .fold(t, |t, r| r.replace_all(&t, "").to_string());
+                    (t, p)
                 })
-                .collect::<Vec<String>>();
+                .collect::<Vec<_>>();
         }
 
         if !config.ignore.is_empty() {
@@ -169,33 +185,233 @@ impl ItemExt for feed_rs::model::Entry {
                 .collect::<Vec<Regex>>();
             tags = tags
                 .into_iter()
-                .filter(|t| !ignore.iter().any(|r| r.is_match(t)))
-                .collect::<Vec<String>>();
+                .filter(|(t, _)| !ignore.iter().any(|r| r.is_match(t)))
+                .collect::<Vec<_>>();
         }
 
         // 大文字小文字を区別しない重複排除
         let mut seen = HashSet::new();
-        tags.retain(|e| seen.insert(e.to_uppercase()));
+        tags.retain(|(e, _)| seen.insert(e.to_uppercase()));
         if let Some(title) = title {
-            tags.retain(|e| !e.contains(title));
+            tags.retain(|(e, _)| !e.contains(title));
+        }
+        tags.retain(|(e, _)| !e.is_empty() && !e.chars().all(char::is_numeric));
+        let tags = tags
+            .iter()
+            .map(|(t, p)| (format!("#{}", sns.to_hashtag(t)), *p))
+            .filter(|(t, _)| t.len() > 1)
+            .collect::<Vec<_>>();
+        // 記事ページに画像がなければフィードの画像を使う
+        let mut image = image.or_else(|| feed_image(self));
+        if let Some(image) = &mut image {
+            if image.alt.is_none() {
+                image.alt = title.cloned();
+            }
         }
-        tags.retain(|e| !e.is_empty() && !e.chars().all(char::is_numeric));
-        if !tags.is_empty() {
-            // 空行を入れるとMastodonで見やすくなる
-            b.append_line();
-            b.append(
-                tags.iter()
-                    .map(|t| {
-                        format!(
-                            "#{}",
-                            TAG_RE.replace_all(&t, "_").trim_matches(|c| c == '_')
-                        )
-                    })
-                    .collect::<Vec<String>>()
-                    .join(" "),
-            );
+        let context = StatusContext {
+            id,
+            feed_title,
+            title: title.map(|t| COMBINE_TAG_RE.replace_all(t, "#$1").to_string()),
+            link: self.links.first().map(|l| l.href.clone()),
+            links: self.links.iter().map(|l| l.href.clone()).collect(),
+            summary: self.summary.as_ref().map(|s| html_to_text(&s.content)),
+            author: self.authors.first().map(author_name),
+            published: self.pub_date_utc().map(|d| d.to_rfc3339()),
+            tags: tags.iter().map(|(t, _)| t.clone()).collect(),
+        };
+        let spoiler_text = match template.spoiler_text {
+            Some(t) => Some(context.render(t)?).filter(|s| !s.trim().is_empty()),
+            None => None,
+        };
+        // CWも文字数制限に含まれる
+        let spoiler_len = spoiler_text.as_ref().map_or(0, |s| s.chars().count());
+        let text = fit_status(context, tags, template.text, limit, spoiler_len)?;
+        Ok(StatusContent {
+            text,
+            spoiler_text,
+            image,
+        })
+    }
+}
+
+/// 文字数制限に収まるまでタグを削り、それでも超える場合はタイトルを省略して本文を作成します。
+fn fit_status(
+    mut context: StatusContext,
+    mut tags: Vec<(String, TagPriority)>,
+    template: &str,
+    limit: &StatusLimit,
+    spoiler_len: usize,
+) -> anyhow::Result<String> {
+    loop {
+        let status = context.render(template)?;
+        let over = (limit.count(&status) + spoiler_len).saturating_sub(limit.max_characters);
+        if over == 0 {
+            return Ok(status);
+        }
+        // 文字数制限を超える場合は優先度の低いタグから削る
+        if let Some((i, _)) = tags.iter().enumerate().max_by_key(|(i, (_, p))| (*p, *i)) {
+            tags.remove(i);
+            context.tags = tags.iter().map(|(t, _)| t.clone()).collect();
+            continue;
+        }
+        // タグを削っても超える場合はタイトルを省略する (リンクは省略しない)
+        let Some(title) = &context.title else {
+            return Ok(status);
+        };
+        let len = title.chars().count();
+        if len <= 1 {
+            return Ok(status);
+        }
+        let keep = len.saturating_sub(over + 1);
+        context.title = Some(title.chars().take(keep).chain(once('…')).collect());
+    }
+}
+
+/// 文字数制限でタグを削る順番 (後ろほど先に削る)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum TagPriority {
+    /// フィードIDと always
+    Fixed,
+    /// 記事のカテゴリー
+    Category,
+    /// 記事ページから抽出したキーワード
+    Page,
+}
+
+/// 投稿する内容
+#[derive(Clone, Debug)]
+pub struct StatusContent {
+    pub text: String,
+    /// CW
+    pub spoiler_text: Option<String>,
+    /// 添付する画像
+    pub image: Option<StatusImage>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusImage {
+    pub url: String,
+    /// 代替テキスト
+    pub alt: Option<String>,
+}
+
+/// 投稿できる文字数の制限
+#[derive(Clone, Debug)]
+pub struct StatusLimit {
+    pub max_characters: usize,
+    /// URLを長さに関係なく固定の文字数として数える場合の文字数
+    pub characters_reserved_per_url: Option<usize>,
+}
+
+impl StatusLimit {
+    /// 投稿の文字数を数えます。
+    pub fn count(&self, status: &str) -> usize {
+        let total = status.chars().count();
+        let Some(per_url) = self.characters_reserved_per_url else {
+            return total;
+        };
+        let urls = URL_RE
+            .find_iter(status)
+            .map(|m| m.as_str().chars().count())
+            .collect::<Vec<_>>();
+        total - urls.iter().sum::<usize>() + urls.len() * per_url
+    }
+}
+
+pub trait SnsExt {
+    fn default_limit(&self) -> StatusLimit;
+    fn to_hashtag(&self, tag: &str) -> String;
+}
+
+impl SnsExt for SNS {
+    /// インスタンスの設定が取得できない場合の文字数制限
+    fn default_limit(&self) -> StatusLimit {
+        let (max_characters, characters_reserved_per_url) = match self {
+            SNS::Mastodon | SNS::Gotosocial => (500, Some(23)),
+            SNS::Firefish => (3000, None),
+            SNS::Pleroma | SNS::Friendica => (5000, None),
+        };
+        StatusLimit {
+            max_characters,
+            characters_reserved_per_url,
         }
-        Ok(b.string().unwrap())
+    }
+
+    /// ハッシュタグとして扱えない文字を `_` に置き換えます。
+    fn to_hashtag(&self, tag: &str) -> String {
+        let re = match self {
+            // Misskey系は記号で区切られない限り1つのタグとして扱われる
+            SNS::Firefish => &MISSKEY_TAG_RE,
+            _ => &TAG_RE,
+        };
+        re.replace_all(tag, "_").trim_matches('_').to_string()
+    }
+}
+
+/// 著者名を取得します。
+/// RSS の author は `email (名前)` 形式のメールアドレスとして格納されるので名前部分を取り出します。
+fn author_name(person: &Person) -> String {
+    match &person.email {
+        Some(email) if person.name == "author" => AUTHOR_RE
+            .captures(email)
+            .map(|c| c[1].to_string())
+            .unwrap_or(email.clone()),
+        _ => person.name.clone(),
+    }
+}
+
+/// 記事ページの og:image または twitter:image を取得します。
+fn find_page_image(doc: &sxd_document::dom::Document, base: &str) -> Option<StatusImage> {
+    let first = |xpath: &str| {
+        evaluate_xpath(doc, xpath)
+            .ok()
+            .map(|v| v.string().trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let url = first("string(//meta[@property='og:image']/@content)").or_else(|| {
+        first("string(//meta[@name='twitter:image' or @property='twitter:image']/@content)")
+    })?;
+    // 相対URLの場合は記事のURLを基準に解決する
+    let url = Url::parse(base)
+        .and_then(|b| b.join(&url))
+        .map(|u| u.to_string())
+        .unwrap_or(url);
+    let alt = first("string(//meta[@property='og:image:alt']/@content)").or_else(|| {
+        first("string(//meta[@name='twitter:image:alt' or @property='twitter:image:alt']/@content)")
+    });
+    Some(StatusImage { url, alt })
+}
+
+/// フィードの media:content などに含まれる画像を取得します。
+fn feed_image(entry: &feed_rs::model::Entry) -> Option<StatusImage> {
+    entry
+        .media
+        .iter()
+        .flat_map(|m| m.content.iter().map(move |c| (c, m.description.as_ref())))
+        .find(|(c, _)| {
+            c.content_type
+                .as_ref()
+                .is_some_and(|t| t.to_string().starts_with("image/"))
+        })
+        .and_then(|(c, description)| {
+            Some(StatusImage {
+                url: c.url.as_ref()?.to_string(),
+                alt: description.map(|d| d.content.clone()),
+            })
+        })
+}
+
+/// HTMLからタグを除いたテキストを取得します。
+fn html_to_text(html: &str) -> String {
+    let package = sxd_html::parse_html(html);
+    let doc = package.as_document();
+    match evaluate_xpath(&doc, "string(/)") {
+        Ok(text) => text
+            .string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        Err(_) => html.to_string(),
     }
 }
 
@@ -242,3 +458,122 @@ async fn decode_text(res: reqwest::Response) -> Result<String, reqwest::Error> {
         Ok(tmp.into_owned())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::DEFAULT_TEMPLATE;
+
+    const MASTODON: StatusLimit = StatusLimit {
+        max_characters: 500,
+        characters_reserved_per_url: Some(23),
+    };
+
+    fn context(title: &str, tags: &[(String, TagPriority)]) -> StatusContext {
+        StatusContext {
+            id: "feed".to_string(),
+            feed_title: None,
+            title: Some(title.to_string()),
+            link: Some("https://example.com/a".to_string()),
+            links: vec!["https://example.com/a".to_string()],
+            summary: None,
+            author: None,
+            published: None,
+            tags: tags.iter().map(|(t, _)| t.clone()).collect(),
+        }
+    }
+
+    fn limit(max_characters: usize) -> StatusLimit {
+        StatusLimit {
+            max_characters,
+            ..MASTODON
+        }
+    }
+
+    #[test]
+    fn count_reserves_characters_per_url() {
+        let status = "記事 https://example.com/a/very/long/path?query=1 と http://b.example";
+        assert_eq!(MASTODON.count(status), 3 + 23 + 3 + 23);
+    }
+
+    #[test]
+    fn count_without_url_reservation() {
+        let limit = StatusLimit {
+            max_characters: 3000,
+            characters_reserved_per_url: None,
+        };
+        assert_eq!(limit.count("記事 https://example.com/a"), 24);
+    }
+
+    #[test]
+    fn fit_status_keeps_status_within_limit() {
+        let tags = vec![("#feed".to_string(), TagPriority::Fixed)];
+        let status = fit_status(
+            context("Hello", &tags),
+            tags,
+            DEFAULT_TEMPLATE,
+            &MASTODON,
+            0,
+        );
+        assert_eq!(status.unwrap(), "Hello\nhttps://example.com/a\n\n#feed");
+    }
+
+    #[test]
+    fn fit_status_drops_low_priority_tags_first() {
+        let tags = vec![
+            ("#feed".to_string(), TagPriority::Fixed),
+            ("#page1".to_string(), TagPriority::Page),
+            ("#cat".to_string(), TagPriority::Category),
+            ("#page2".to_string(), TagPriority::Page),
+        ];
+        // 本文 37 文字 + タグ 24 文字のうち、ページのタグ 2 つを削れば収まる
+        let status = fit_status(
+            context("Hello world", &tags),
+            tags,
+            DEFAULT_TEMPLATE,
+            &limit(50),
+            0,
+        );
+        assert_eq!(
+            status.unwrap(),
+            "Hello world\nhttps://example.com/a\n\n#feed #cat"
+        );
+    }
+
+    #[test]
+    fn fit_status_truncates_title_after_tags() {
+        let tags = vec![("#feed".to_string(), TagPriority::Fixed)];
+        let status = fit_status(
+            context("Hello world", &tags),
+            tags,
+            DEFAULT_TEMPLATE,
+            &limit(30),
+            0,
+        );
+        assert_eq!(status.unwrap(), "Hell…\nhttps://example.com/a\n");
+    }
+
+    #[test]
+    fn fit_status_counts_spoiler_text() {
+        let status = fit_status(
+            context("Hello world", &[]),
+            vec![],
+            DEFAULT_TEMPLATE,
+            &limit(36),
+            6,
+        );
+        assert_eq!(status.unwrap(), "Hell…\nhttps://example.com/a\n");
+    }
+
+    #[test]
+    fn fit_status_never_truncates_link() {
+        let status = fit_status(
+            context("Hello", &[]),
+            vec![],
+            DEFAULT_TEMPLATE,
+            &limit(10),
+            0,
+        );
+        assert_eq!(status.unwrap(), "…\nhttps://example.com/a\n");
+    }
+}
@@ -1,18 +1,40 @@
 use chrono::{Duration, Utc};
 use feed_rs::model::Feed;
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::RequestBuilder;
 use sea_orm::entity::prelude::*;
 use sea_orm::ActiveValue::*;
+use sea_orm::IntoActiveModel;
 
-use crate::constants::{MIN_WAIT, MAX_WAIT};
-use crate::ext_trait::ItemExt;
+use crate::ext_trait::{ItemExt, ISO8601};
+use crate::fetch_hint::FeedHints;
+use crate::schema::FeedSchedule;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "feed_info")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub source: String,
+    /// フィードのタイトル
+    pub title: Option<String>,
     pub last_fetch: DateTimeUtc,
     pub next_fetch: DateTimeUtc,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// フィードが示す取得の目安 (JSON)
+    pub hints: Option<String>,
+    /// next_fetch を決めた理由
+    pub next_fetch_reason: Option<String>,
+    /// 連続して取得に失敗した回数
+    #[sea_orm(default_value = 0)]
+    pub failures: i32,
+    /// 最後に失敗した理由
+    pub last_error: Option<String>,
+    /// 失敗し続けている場合の最初の失敗日時
+    pub failing_since: Option<DateTimeUtc>,
+    /// 長期間失敗し続けて停止扱いになっているか
+    #[sea_orm(default_value = false)]
+    pub dead: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -24,34 +46,198 @@ impl Model {
     pub fn new(source: String) -> Self {
         Self {
             source,
+            title: None,
             last_fetch: DateTimeUtc::UNIX_EPOCH,
             next_fetch: DateTimeUtc::UNIX_EPOCH,
+            etag: None,
+            last_modified: None,
+            hints: None,
+            next_fetch_reason: None,
+            failures: 0,
+            last_error: None,
+            failing_since: None,
+            dead: false,
+        }
+    }
+}
+
+impl Entity {
+    /// フィードの状態を取得します。初めてのフィードの場合は登録します。
+    pub async fn find_or_insert(
+        db: &DatabaseConnection,
+        source: &str,
+    ) -> Result<ActiveModel, DbErr> {
+        match Self::find_by_id(source).one(db).await? {
+            Some(info) => Ok(info.into_active_model()),
+            None => {
+                let info = Model::new(source.to_string()).into_active_model();
+                Ok(info.insert(db).await?.into_active_model())
+            }
         }
     }
 }
 
 impl ActiveModel {
-    pub fn update_next_fetch(&mut self, feed: &Feed) -> Duration {
-        if self.last_fetch.as_ref() == &DateTimeUtc::UNIX_EPOCH {
-            let duration = Self::get_first_duration(feed);
-            self.last_fetch = Set(Utc::now());
-            self.next_fetch = Set(*self.last_fetch.as_ref() + duration);
-            duration
+    /// 前回のレスポンスの ETag / Last-Modified を条件付きリクエストのヘッダーとして付与します。
+    pub fn conditional_request(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = self.etag.as_ref() {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = self.last_modified.as_ref() {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        req
+    }
+
+    /// レスポンスの ETag / Last-Modified を次回の条件付きリクエスト用に保存します。
+    pub fn update_validators(&mut self, headers: &HeaderMap) {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        self.etag = Set(get(ETAG));
+        self.last_modified = Set(get(LAST_MODIFIED));
+    }
+
+    /// 取得に成功したので失敗の記録を消します。停止扱いから復帰した場合は true を返します。
+    pub fn record_success(&mut self) -> bool {
+        let recovered = *self.dead.as_ref();
+        if *self.failures.as_ref() != 0 {
+            self.failures = Set(0);
+            self.last_error = Set(None);
+            self.failing_since = Set(None);
+            self.dead = Set(false);
+        }
+        recovered
+    }
+
+    /// 取得の失敗を記録し、失敗した回数に応じて min_wait から倍々に待機時間を延ばします。
+    /// dead_after の間失敗し続けた場合は停止扱いにし、初めて停止扱いになった場合は true を返します。
+    pub fn record_failure(&mut self, error: String, schedule: &FeedSchedule) -> (Duration, bool) {
+        let now = Utc::now();
+        let failures = *self.failures.as_ref() + 1;
+        let since = self.failing_since.as_ref().unwrap_or(now);
+        // 上限に達した後も桁あふれしないように指数を抑える
+        let duration = (schedule.min_wait * 2i32.pow((failures - 1).clamp(0, 20) as u32))
+            .min(schedule.max_backoff);
+        let newly_dead = !*self.dead.as_ref() && now - since >= schedule.dead_after;
+        self.failures = Set(failures);
+        self.last_error = Set(Some(error));
+        self.failing_since = Set(Some(since));
+        if newly_dead {
+            self.dead = Set(true);
+        }
+        self.next_fetch = Set(now + duration);
+        self.next_fetch_reason = Set(Some(format!("backoff after {} failures", failures)));
+        (duration, newly_dead)
+    }
+
+    /// 前回保存した取得の目安を返します。
+    pub fn hints(&self) -> FeedHints {
+        self.hints
+            .as_ref()
+            .as_deref()
+            .and_then(|h| serde_json::from_str(h).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn set_hints(&mut self, hints: &FeedHints) {
+        let hints = serde_json::to_string(hints).ok();
+        if *self.hints.as_ref() != hints {
+            self.hints = Set(hints);
+        }
+    }
+
+    /// 304 Not Modified の場合は新着なしとして、前回のチェック間隔から1.5倍の値を使用します。
+    pub fn update_next_fetch_not_modified(
+        &mut self,
+        schedule: &FeedSchedule,
+        max_age: Option<Duration>,
+    ) -> Duration {
+        let duration = (Utc::now() - *self.last_fetch.as_ref()) * 3 / 2;
+        self.schedule_next(duration, "not modified: 1.5x", schedule, max_age)
+    }
+
+    /// 次の取得日時を決めます。
+    pub fn update_next_fetch(
+        &mut self,
+        feed: &Feed,
+        schedule: &FeedSchedule,
+        max_age: Option<Duration>,
+    ) -> Duration {
+        let (duration, reason) = if self.last_fetch.as_ref() == &DateTimeUtc::UNIX_EPOCH {
+            Self::get_first_duration(feed)
         } else {
-            let duration = Self::get_next_duration(feed, self.last_fetch.as_ref())
-                .max(*MIN_WAIT)
-                .min(*MAX_WAIT);
-            self.last_fetch = Set(Utc::now());
-            self.next_fetch = Set(*self.last_fetch.as_ref() + duration);
-            duration
+            Self::get_next_duration(feed, self.last_fetch.as_ref())
+        };
+        self.schedule_next(duration, reason, schedule, max_age)
+    }
+
+    /// Retry-After に従って次の取得日時を決めます。サーバーの指示なので max_wait は超えても構いません。
+    pub fn update_next_fetch_retry_after(
+        &mut self,
+        retry_after: Duration,
+        schedule: &FeedSchedule,
+    ) -> Duration {
+        let now = Utc::now();
+        let mut reasons = vec![format!("Retry-After {}", retry_after.to_iso8601())];
+        let mut duration = retry_after;
+        if duration < schedule.min_wait {
+            duration = schedule.min_wait;
+            reasons.push("min_wait".to_string());
+        }
+        self.last_fetch = Set(now);
+        self.next_fetch = Set(now + duration);
+        self.next_fetch_reason = Set(Some(reasons.join(", ")));
+        duration
+    }
+
+    /// 記事の間隔から求めた待機時間にフィードやレスポンスヘッダーの目安を反映し、理由とともに保存します。
+    /// 更新の間隔と max-age より早くは取得せず、min_wait と max_wait の範囲に収めた上で、
+    /// skipHours / skipDays に当たる場合は当たらなくなるまで遅らせます。
+    fn schedule_next(
+        &mut self,
+        duration: Duration,
+        reason: &str,
+        schedule: &FeedSchedule,
+        max_age: Option<Duration>,
+    ) -> Duration {
+        let hints = self.hints();
+        let mut duration = duration;
+        let mut reasons = vec![reason.to_string()];
+        if let Some(period) = hints.update_period().filter(|p| duration < *p) {
+            duration = period;
+            reasons.push(format!("sy:updatePeriod {}", period.to_iso8601()));
+        }
+        if let Some(max_age) = max_age.filter(|m| duration < *m) {
+            duration = max_age;
+            reasons.push(format!("max-age {}", max_age.to_iso8601()));
+        }
+        if duration < schedule.min_wait {
+            duration = schedule.min_wait;
+            reasons.push("min_wait".to_string());
+        } else if duration > schedule.max_wait {
+            duration = schedule.max_wait;
+            reasons.push("max_wait".to_string());
         }
+        let now = Utc::now();
+        let mut next = now + duration;
+        if let Some(deferred) = hints.defer_skipped(next) {
+            next = deferred;
+            reasons.push("skipHours/skipDays".to_string());
+        }
+        self.last_fetch = Set(now);
+        self.next_fetch = Set(next);
+        self.next_fetch_reason = Set(Some(reasons.join(", ")));
+        next - now
     }
 
     /// 最初のフィードの場合、現在の時間を使用します。
     /// フィードの時間寿命（TTL）と最初の2つのエントリーの時間差に基づいて、フィードの期間を計算します。
     /// エントリーが2つ未満の場合、TTL / 6 が使用されます。
-    /// 5分未満の場合、5分を使用します。
-    fn get_first_duration(feed: &Feed) -> Duration {
+    fn get_first_duration(feed: &Feed) -> (Duration, &'static str) {
         let now = Utc::now();
         let ttl = Duration::minutes(feed.ttl.unwrap_or(60) as i64);
         let mut pubs = feed
@@ -62,11 +248,14 @@ impl ActiveModel {
         pubs.sort();
         pubs.dedup();
         if pubs.len() > 2 {
-            let first = pubs.get(0).unwrap();
+            let first = pubs.first().unwrap();
             let second = pubs.get(1).unwrap();
-            MIN_WAIT.max(ttl.min(**second - **first) / 6)
+            (
+                ttl.min(**second - **first) / 6,
+                "first: ttl or entry interval / 6",
+            )
         } else {
-            MIN_WAIT.max(ttl / 6)
+            (ttl / 6, "first: ttl / 6")
         }
     }
 
@@ -75,55 +264,56 @@ impl ActiveModel {
     /// 前回のチェックから1回も投稿がないかつ間隔が中央値の1/6未満なら、中央値の1/6を使用します。
     /// 前回のチェックから1回も投稿がないかつ間隔が中央値未満なら、前回の1.1倍の値を使用します。(中央値を超えない)
     /// 中央値を超えるまでに1回も投稿がなければ、それ以降から前回の1.5倍の値を使用します。
-    fn get_next_duration(feed: &Feed, last_fetch: &DateTimeUtc) -> Duration {
+    fn get_next_duration(feed: &Feed, last_fetch: &DateTimeUtc) -> (Duration, &'static str) {
         // 前回のチェックから現在時刻の間隔の取得
         let duration = Utc::now() - *last_fetch;
 
+        // 公開日時のない記事は間隔の計算に使えないので除外
+        let mut pubs = feed
+            .entries
+            .iter()
+            .filter_map(|e| e.pub_date_utc())
+            .collect::<Vec<_>>();
+        pubs.sort();
+
         // そもそも1回も投稿がなければ、前回のチェック間隔から1.5倍の値を使用
-        if feed.entries.is_empty() {
-            return duration * 3 / 2;
+        if pubs.is_empty() {
+            return (duration * 3 / 2, "no dated entries: 1.5x");
         }
 
         // 前回のチェックからの投稿を取得
-        let mut last_posted: Vec<_> = feed
-            .entries
-            .iter()
-            .filter(|e| e.pub_date_utc().unwrap() > last_fetch)
-            .collect();
-        last_posted.sort_by_key(|e| e.pub_date_utc().unwrap());
+        let last_posted = pubs.iter().filter(|p| **p > last_fetch).collect::<Vec<_>>();
         // 前回のチェックから2回以上投稿があれば、半分の値を使用
         if last_posted.len() >= 2 {
-            return duration / 2;
+            return (duration / 2, "2+ new entries: 0.5x");
         }
         // 前回のチェックから1回投稿があれば、前回の投稿からの同じ間隔を使用
         if last_posted.len() == 1 {
-            return *last_posted[0].pub_date_utc().unwrap() - *last_fetch;
+            return (
+                **last_posted[0] - *last_fetch,
+                "1 new entry: since last fetch",
+            );
         }
 
         // 前回のチェックから1回も投稿がなければ、
-        let mut tmp = feed.entries.clone();
-        tmp.sort_by_key(|e| *e.pub_date_utc().unwrap());
-        let mut durations = Vec::with_capacity(tmp.len() - 1);
-        for (prev, next) in tmp.iter().zip(tmp.iter().skip(1)) {
-            durations.push(*next.pub_date_utc().unwrap() - *prev.pub_date_utc().unwrap());
+        let mut durations = Vec::with_capacity(pubs.len() - 1);
+        for (prev, next) in pubs.iter().zip(pubs.iter().skip(1)) {
+            durations.push(**next - **prev);
         }
         // 5分未満は連続投稿扱いで無視
-        durations = durations
-            .into_iter()
-            .filter(|d| *d > Duration::minutes(5))
-            .collect();
+        durations.retain(|d| *d > Duration::minutes(5));
         // 1回も投稿がなければ、前回のチェック間隔から1.5倍の値を使用
         if durations.is_empty() {
-            return duration * 3 / 2;
+            return (duration * 3 / 2, "no entry intervals: 1.5x");
         }
         let median = median(durations);
         let median6 = median / 6;
         if duration < median6 {
-            return median6;
+            (median6, "no new entries: median / 6")
         } else if duration < median {
-            return duration * 11 / 10;
+            (duration * 11 / 10, "no new entries: 1.1x")
         } else {
-            return duration * 3 / 2;
+            (duration * 3 / 2, "no new entries over median: 1.5x")
         }
     }
 }
@@ -132,9 +322,67 @@ fn median(mut durations: Vec<Duration>) -> Duration {
     // 中央値の算出
     durations.sort();
     let len = durations.len();
-    if len % 2 == 0 {
+    if len.is_multiple_of(2) {
         (durations[len / 2 - 1] + durations[len / 2]) / 2
     } else {
         durations[len / 2]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> FeedSchedule {
+        FeedSchedule {
+            min_wait: Duration::minutes(5),
+            max_wait: Duration::minutes(60),
+            queue_interval: Duration::seconds(1),
+            max_backoff: Duration::minutes(60),
+            dead_after: Duration::days(1),
+        }
+    }
+
+    #[test]
+    fn record_failure_doubles_until_max_backoff() {
+        let mut info = Model::new("feed".to_string()).into_active_model();
+        let waits = (0..6)
+            .map(|_| info.record_failure("error".to_string(), &schedule()).0)
+            .map(|d| d.num_minutes())
+            .collect::<Vec<_>>();
+        assert_eq!(waits, [5, 10, 20, 40, 60, 60]);
+        assert_eq!(info.failures, Set(6));
+        assert_eq!(
+            info.next_fetch_reason,
+            Set(Some("backoff after 6 failures".to_string()))
+        );
+    }
+
+    #[test]
+    fn record_failure_does_not_overflow() {
+        let mut info = Model {
+            failures: i32::MAX - 1,
+            ..Model::new("feed".to_string())
+        }
+        .into_active_model();
+        let schedule = FeedSchedule {
+            max_backoff: Duration::days(365),
+            ..schedule()
+        };
+        let (wait, _) = info.record_failure("error".to_string(), &schedule);
+        assert_eq!(wait, schedule.max_backoff);
+    }
+
+    #[test]
+    fn record_failure_marks_dead_once() {
+        let mut info = Model {
+            failing_since: Some(Utc::now() - Duration::days(2)),
+            ..Model::new("feed".to_string())
+        }
+        .into_active_model();
+        assert!(info.record_failure("error".to_string(), &schedule()).1);
+        assert!(!info.record_failure("error".to_string(), &schedule()).1);
+        assert!(info.record_success());
+        assert_eq!(info.failures, Set(0));
+    }
+}
@@ -0,0 +1,261 @@
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc, Weekday};
+use feed_rs::model::{Feed, FeedType};
+use quick_xml::{events::Event, Reader};
+use reqwest::header::{HeaderMap, CACHE_CONTROL, RETRY_AFTER};
+use serde_derive::{Deserialize, Serialize};
+
+/// フィード自身が示す取得の目安
+/// 304 Not Modified の場合にも使えるように保存しておきます。
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedHints {
+    /// RSS の skipHours (UTC の時, 0-23)
+    pub skip_hours: Vec<u32>,
+    /// RSS の skipDays (月曜日を0とする曜日)
+    pub skip_days: Vec<u32>,
+    /// Syndication モジュールの updatePeriod / updateFrequency から求めた更新の間隔 (秒)
+    pub update_period: Option<i64>,
+}
+
+impl FeedHints {
+    /// フィードから取得の目安を読み取ります。
+    /// feed_rs は skipHours / skipDays や Syndication モジュールの項目を保持しないため、
+    /// 解析済みの本文を DOM を作らずに読み流して探します。
+    /// XMLとして読めなかった場合は None を返します (前回の目安を使い続ける)。
+    pub fn parse(feed: &Feed, content: &[u8]) -> Option<Self> {
+        // JSON Feed には該当する項目がない
+        if feed.feed_type == FeedType::JSON {
+            return Some(Self::default());
+        }
+        let mut reader = Reader::from_reader(content);
+        reader.config_mut().trim_text(true);
+        let (mut hours, mut days, mut period, mut frequency) = (vec![], vec![], vec![], vec![]);
+        // 名前空間の接頭辞はフィードごとに異なるのでローカル名で探す
+        let mut path = vec![];
+        loop {
+            match reader.read_event().ok()? {
+                Event::Start(e) => path.push(e.local_name().as_ref().to_vec()),
+                Event::End(_) => {
+                    path.pop();
+                }
+                Event::Text(text) => {
+                    let values = match path.as_slice() {
+                        [.., parent, name] if parent == b"skipHours" && name == b"hour" => {
+                            &mut hours
+                        }
+                        [.., parent, name] if parent == b"skipDays" && name == b"day" => &mut days,
+                        [.., name] if name == b"updatePeriod" => &mut period,
+                        [.., name] if name == b"updateFrequency" => &mut frequency,
+                        _ => continue,
+                    };
+                    values.push(text.decode().ok()?.trim().to_string());
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        let skip_hours = hours
+            .iter()
+            .filter_map(|h| h.parse::<u32>().ok())
+            // 1-24 で書かれたフィードもあるので24時は0時として扱う
+            .map(|h| h % 24)
+            .collect();
+        let skip_days = days
+            .iter()
+            .filter_map(|d| d.parse::<Weekday>().ok())
+            .map(|d| d.num_days_from_monday())
+            .collect();
+
+        let update_period = if period.is_empty() && frequency.is_empty() {
+            None
+        } else {
+            // 省略時は daily と 1
+            let period = match period.first().map(|p| p.as_str()).unwrap_or("daily") {
+                "hourly" => Some(Duration::hours(1)),
+                "daily" => Some(Duration::days(1)),
+                "weekly" => Some(Duration::weeks(1)),
+                "monthly" => Some(Duration::days(30)),
+                "yearly" => Some(Duration::days(365)),
+                _ => None,
+            };
+            let frequency = frequency
+                .first()
+                .and_then(|f| f.parse::<i32>().ok())
+                .filter(|f| *f > 0)
+                .unwrap_or(1);
+            period.map(|p| (p / frequency).num_seconds())
+        };
+
+        Some(Self {
+            skip_hours,
+            skip_days,
+            update_period,
+        })
+    }
+
+    pub fn update_period(&self) -> Option<Duration> {
+        self.update_period.map(Duration::seconds)
+    }
+
+    /// skipHours / skipDays に当たる場合は、当たらなくなる最初の時刻を返します。
+    /// 当たらない場合や全ての時間が除外されている場合は None を返します。
+    pub fn defer_skipped(&self, next: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let skipped = |t: &DateTime<Utc>| {
+            self.skip_hours.contains(&t.hour())
+                || self.skip_days.contains(&t.weekday().num_days_from_monday())
+        };
+        if !skipped(&next) {
+            return None;
+        }
+        let mut time = next.with_minute(0)?.with_second(0)?.with_nanosecond(0)?;
+        // 1週間分進めても当たる場合は全ての時間が除外されている
+        for _ in 0..24 * 7 {
+            time += Duration::hours(1);
+            if !skipped(&time) {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+/// Cache-Control の max-age を返します。no-cache や no-store の場合は None を返します。
+pub fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+    let directives = value
+        .split(',')
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if directives
+        .iter()
+        .any(|d| d == "no-cache" || d == "no-store")
+    {
+        return None;
+    }
+    directives
+        .iter()
+        .find_map(|d| d.strip_prefix("max-age=")?.parse::<i64>().ok())
+        .filter(|s| *s > 0)
+        .map(Duration::seconds)
+}
+
+/// Retry-After の秒数または日時から待機時間を返します。
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Some(Duration::seconds(seconds.max(0)));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - Utc::now()).max(Duration::zero()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::parse_feed;
+    use chrono::TimeZone;
+    use reqwest::header::HeaderValue;
+
+    fn headers(name: reqwest::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    const RSS: &[u8] =
+        br#"<rss version="2.0" xmlns:sy="http://purl.org/rss/1.0/modules/syndication/"><channel>
+        <title>t</title><link>https://example.com/</link><description>d</description>
+        <skipHours><hour>24</hour><hour>1</hour></skipHours>
+        <skipDays><day>Sunday</day></skipDays>
+        <sy:updatePeriod>hourly</sy:updatePeriod><sy:updateFrequency>2</sy:updateFrequency>
+        </channel></rss>"#;
+
+    fn parse(content: &[u8]) -> Option<FeedHints> {
+        let feed = parse_feed("https://example.com/feed", content).unwrap();
+        FeedHints::parse(&feed, content)
+    }
+
+    #[test]
+    fn parse_wraps_hour_24_to_0() {
+        let hints = parse(RSS).unwrap();
+        assert_eq!(hints.skip_hours, [0, 1]);
+        assert_eq!(hints.skip_days, [6]);
+        assert_eq!(hints.update_period(), Some(Duration::minutes(30)));
+    }
+
+    #[test]
+    fn parse_json_feed_has_no_hints() {
+        let hints =
+            parse(br#"{"version": "https://jsonfeed.org/version/1.1", "title": "t", "items": []}"#);
+        assert_eq!(hints, Some(FeedHints::default()));
+    }
+
+    #[test]
+    fn parse_failure_keeps_previous_hints() {
+        let feed = parse_feed("https://example.com/feed", RSS).unwrap();
+        assert_eq!(FeedHints::parse(&feed, b"<rss><channel></rss>"), None);
+    }
+
+    #[test]
+    fn defer_skipped_moves_to_next_allowed_hour() {
+        let hints = FeedHints {
+            skip_hours: vec![23, 0],
+            ..Default::default()
+        };
+        let next = Utc.with_ymd_and_hms(2024, 1, 1, 23, 30, 0).unwrap();
+        assert_eq!(
+            hints.defer_skipped(next),
+            Some(Utc.with_ymd_and_hms(2024, 1, 2, 1, 0, 0).unwrap())
+        );
+        let next = Utc.with_ymd_and_hms(2024, 1, 1, 12, 30, 0).unwrap();
+        assert_eq!(hints.defer_skipped(next), None);
+    }
+
+    #[test]
+    fn defer_skipped_gives_up_when_all_hours_are_skipped() {
+        let hints = FeedHints {
+            skip_hours: (0..24).collect(),
+            ..Default::default()
+        };
+        let next = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(hints.defer_skipped(next), None);
+        let hints = FeedHints {
+            skip_days: (0..7).collect(),
+            ..Default::default()
+        };
+        assert_eq!(hints.defer_skipped(next), None);
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            retry_after(&headers(RETRY_AFTER, "120")),
+            Some(Duration::seconds(120))
+        );
+        assert_eq!(retry_after(&headers(RETRY_AFTER, "soon")), None);
+    }
+
+    #[test]
+    fn retry_after_rfc2822_date() {
+        let date = (Utc::now() + Duration::minutes(10)).to_rfc2822();
+        let wait = retry_after(&headers(RETRY_AFTER, &date)).unwrap();
+        assert!(wait > Duration::minutes(9) && wait <= Duration::minutes(10));
+        // 過去の日時はすぐに再試行する
+        let wait = retry_after(&headers(RETRY_AFTER, "Mon, 01 Jan 2024 00:00:00 GMT"));
+        assert_eq!(wait, Some(Duration::zero()));
+    }
+
+    #[test]
+    fn max_age_from_cache_control() {
+        assert_eq!(
+            max_age(&headers(CACHE_CONTROL, "public, Max-Age=600")),
+            Some(Duration::minutes(10))
+        );
+        assert_eq!(
+            max_age(&headers(CACHE_CONTROL, "no-cache, max-age=600")),
+            None
+        );
+        assert_eq!(max_age(&headers(CACHE_CONTROL, "max-age=0")), None);
+        assert_eq!(max_age(&HeaderMap::new()), None);
+    }
+}
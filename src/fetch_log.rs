@@ -0,0 +1,143 @@
+use chrono::{Duration, Utc};
+use sea_orm::{
+    entity::prelude::*,
+    sea_query::{Alias, CaseStatement, Func, SimpleExpr},
+    FromQueryResult, QueryOrder, QuerySelect, Set,
+};
+use serde_derive::Serialize;
+
+use crate::feed_info;
+
+/// フィードの取得履歴 (取得間隔の調整が適切かを後から確認するために使う)
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "fetch_log")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(indexed)]
+    pub source: String,
+    #[sea_orm(indexed)]
+    pub fetched_at: DateTimeUtc,
+    /// HTTPステータス (通信に失敗した場合は None)
+    pub status: Option<i32>,
+    /// 受信したフィードの大きさ (バイト)
+    pub bytes: Option<i64>,
+    /// フィードに含まれていた記事の数
+    pub entries: Option<i32>,
+    /// 新着として投稿キューに追加した記事の数
+    #[sea_orm(default_value = 0)]
+    pub new_entries: i32,
+    /// 新着の記事が公開されてから検出するまでの最長の時間 (秒)
+    pub latency: Option<i64>,
+    /// 次に取得するまでの間隔 (秒)
+    pub next_interval: Option<i64>,
+    /// 次の取得日時を決めた規則
+    pub rule: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl ActiveModel {
+    /// 取得を始めた時点の履歴を作成します。
+    pub fn start(source: &str) -> Self {
+        Self {
+            source: Set(source.to_string()),
+            fetched_at: Set(Utc::now()),
+            new_entries: Set(0),
+            ..Default::default()
+        }
+    }
+
+    /// フィードの状態から次の取得までの間隔と規則を記録します。
+    pub fn scheduled(&mut self, info: &feed_info::ActiveModel) {
+        let interval = *info.next_fetch.as_ref() - *self.fetched_at.as_ref();
+        self.next_interval = Set(Some(interval.num_seconds()));
+        self.rule = Set(info.next_fetch_reason.as_ref().clone());
+    }
+
+    /// 新着の記事を記録します。公開日時のある記事のうち最も待たせたものを検出の遅れとします。
+    pub fn found(&mut self, pub_dates: &[DateTimeUtc]) {
+        let fetched_at = *self.fetched_at.as_ref();
+        self.new_entries = Set(pub_dates.len() as i32);
+        self.latency = Set(pub_dates
+            .iter()
+            .map(|p| (fetched_at - *p).max(Duration::zero()).num_seconds())
+            .max());
+    }
+}
+
+/// フィードごとの取得履歴の集計
+#[derive(Debug, Serialize, FromQueryResult)]
+pub struct FetchStats {
+    pub source: String,
+    pub fetches: i64,
+    pub errors: i64,
+    pub new_entries: i64,
+    /// 新着を検出するまでの時間の平均 (秒)
+    pub avg_latency: Option<i64>,
+    /// 新着を検出するまでの時間の最長 (秒)
+    pub max_latency: Option<i64>,
+    /// 取得に成功した場合の取得の間隔の平均 (秒)
+    pub avg_interval: Option<i64>,
+}
+
+impl Entity {
+    /// 保存期間を過ぎた履歴を削除し、削除した件数を返します。
+    pub async fn prune(db: &DatabaseConnection, retention: Duration) -> Result<u64, anyhow::Error> {
+        let res = Self::delete_many()
+            .filter(Column::FetchedAt.lt(Utc::now() - retention))
+            .exec(db)
+            .await?;
+        Ok(res.rows_affected)
+    }
+
+    /// フィードの直近の履歴を新しい順に取得します。
+    pub async fn find_recent(
+        db: &DatabaseConnection,
+        source: &str,
+        limit: u64,
+    ) -> Result<Vec<Model>, anyhow::Error> {
+        let logs = Self::find()
+            .filter(Column::Source.eq(source))
+            .order_by_desc(Column::FetchedAt)
+            .limit(limit)
+            .all(db)
+            .await?;
+        Ok(logs)
+    }
+
+    /// 指定日時以降の履歴をフィードごとに集計します。
+    /// 失敗した取得はバックオフで間隔が延びるため、取得の間隔の平均には含めません。
+    pub async fn stats(
+        db: &DatabaseConnection,
+        since: DateTimeUtc,
+    ) -> Result<Vec<FetchStats>, anyhow::Error> {
+        // 合計と平均の型がDBごとに異なるので整数に揃える
+        let bigint = |e: SimpleExpr| e.cast_as(Alias::new("bigint"));
+        let succeeded_interval =
+            CaseStatement::new().case(Column::Error.is_null(), Expr::col(Column::NextInterval));
+        let stats = Self::find()
+            .select_only()
+            .column(Column::Source)
+            .column_as(Column::Id.count(), "fetches")
+            .column_as(Column::Error.count(), "errors")
+            .column_as(bigint(Column::NewEntries.sum()), "new_entries")
+            .column_as(
+                bigint(Func::avg(Expr::col(Column::Latency)).into()),
+                "avg_latency",
+            )
+            .column_as(Column::Latency.max(), "max_latency")
+            .column_as(bigint(Func::avg(succeeded_interval).into()), "avg_interval")
+            .filter(Column::FetchedAt.gte(since))
+            .group_by(Column::Source)
+            .order_by_asc(Column::Source)
+            .into_model::<FetchStats>()
+            .all(db)
+            .await?;
+        Ok(stats)
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+
+use crate::schema::{Config, FeedConfig, Secret, StatusConfig, TagConfig};
+
+/// 旧バージョンの設定ファイル (sources.yml)
+#[derive(Debug, Deserialize)]
+pub struct LegacyConfig {
+    pub sources: Vec<LegacySource>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacySource {
+    pub id: String,
+    pub dest: LegacyDest,
+    pub source: LegacyFeed,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyDest {
+    pub account: Option<LegacyAccount>,
+    pub mastodon: LegacyMastodon,
+    pub tags: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyAccount {
+    pub bot: Option<bool>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyMastodon {
+    pub url: String,
+    pub token: Secret,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyFeed {
+    pub feed: String,
+    pub remote_keyword: Option<LegacyKeyword>,
+    pub remote_xpath_tags: Option<String>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyKeyword {
+    pub enable: Option<bool>,
+    pub ignore: Option<Vec<String>>,
+    pub replace_rules: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+/// 変換できなかった項目。項目ごとに該当するフィードのIDをまとめます。
+pub type Unmapped = BTreeMap<String, Vec<String>>;
+
+impl LegacyConfig {
+    /// 現在の設定に変換します。対応する項目がないものは変換できなかった項目として返します。
+    /// 最も多く使われている投稿先のサーバーを全体の base_url とし、異なるものだけフィードに残します。
+    pub fn convert(self) -> (Config, Unmapped) {
+        let mut unmapped = Unmapped::new();
+        for key in self.other.keys() {
+            unmapped.entry(key.clone()).or_default();
+        }
+
+        let mut counts = HashMap::new();
+        for source in &self.sources {
+            *counts.entry(source.dest.mastodon.url.as_str()).or_insert(0) += 1;
+        }
+        let base_url = counts
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+            .map(|(url, _)| url.to_string())
+            .unwrap_or_default();
+
+        let feeds = self
+            .sources
+            .into_iter()
+            .map(|source| source.convert(&base_url, &mut unmapped))
+            .collect();
+        let config = Config {
+            base_url,
+            sns: None,
+            tag: None,
+            template: None,
+            media: None,
+            status: StatusConfig::default(),
+            min_wait: None,
+            max_wait: None,
+            queue_interval: None,
+            post_interval: None,
+            config_interval: None,
+            max_backoff: None,
+            dead_after: None,
+            fetch_log_days: None,
+            feeds,
+        };
+        (config, unmapped)
+    }
+}
+
+impl LegacySource {
+    fn convert(self, base_url: &str, unmapped: &mut Unmapped) -> FeedConfig {
+        let id = self.id;
+        let mut report = |path: &str, other: &BTreeMap<String, Value>| {
+            for key in other.keys() {
+                let key = format!("{}{}", path, key);
+                unmapped.entry(key).or_default().push(id.clone());
+            }
+        };
+        report("sources[].", &self.other);
+        report("sources[].dest.", &self.dest.other);
+        report("sources[].dest.mastodon.", &self.dest.mastodon.other);
+        report("sources[].source.", &self.source.other);
+        if let Some(account) = &self.dest.account {
+            report("sources[].dest.account.", &account.other);
+        }
+        if let Some(keyword) = &self.source.remote_keyword {
+            report("sources[].source.remote_keyword.", &keyword.other);
+        }
+        // ボットの設定はアカウント側で行うため対応する項目がない
+        if self.dest.account.as_ref().is_some_and(|a| a.bot.is_some()) {
+            unmapped
+                .entry("sources[].dest.account.bot".to_string())
+                .or_default()
+                .push(id.clone());
+        }
+
+        let keyword = self.source.remote_keyword;
+        let tag = TagConfig {
+            // フィードのIDは常にハッシュタグになるので除く
+            always: self
+                .dest
+                .tags
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|t| !t.eq_ignore_ascii_case(&id))
+                .collect(),
+            ignore: keyword
+                .as_ref()
+                .and_then(|k| k.ignore.clone())
+                .unwrap_or_default(),
+            replace: keyword
+                .as_ref()
+                .and_then(|k| k.replace_rules.clone())
+                .unwrap_or_default(),
+            xpath: self.source.remote_xpath_tags,
+            // 既定で抽出するので無効な場合のみ指定する
+            keywords: keyword.and_then(|k| k.enable).filter(|e| !e),
+        };
+        let mastodon = self.dest.mastodon;
+        FeedConfig {
+            url: self.source.feed,
+            base_url: (mastodon.url != base_url).then_some(mastodon.url),
+            sns: None,
+            token: Some(mastodon.token),
+            tag: (tag != TagConfig::new()).then_some(tag),
+            template: None,
+            media: None,
+            status: StatusConfig::default(),
+            on_update: None,
+            on_retract: None,
+            retract_note: None,
+            destinations: None,
+            min_wait: None,
+            max_wait: None,
+            id,
+        }
+    }
+}
+
+/// 出力する設定から省略された項目を取り除きます。
+pub fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Mapping(map) => Value::Mapping(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, strip_nulls(v)))
+                .collect(),
+        ),
+        Value::Sequence(seq) => Value::Sequence(seq.into_iter().map(strip_nulls).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+interval: 10
+sources:
+- id: news
+  dest:
+    account:
+      bot: true
+    mastodon:
+      url: https://a.example
+      token: t1
+    tags: [News, daily]
+  source:
+    feed: https://news.example/rss
+    remote_keyword:
+      enable: false
+      ignore: [ad]
+      replace_rules: ['a/b']
+    remote_xpath_tags: //meta
+- id: blog
+  dest:
+    mastodon:
+      url: https://b.example
+      token: t2
+      visibility: public
+  source:
+    feed: https://blog.example/rss
+- id: blog2
+  dest:
+    mastodon:
+      url: https://a.example
+      token: t3
+  source:
+    feed: https://blog2.example/rss
+";
+
+    fn convert() -> (Config, Unmapped) {
+        serde_yaml::from_str::<LegacyConfig>(TEXT)
+            .unwrap()
+            .convert()
+    }
+
+    #[test]
+    fn uses_most_common_server_as_base_url() {
+        let (config, _) = convert();
+        assert_eq!(config.base_url, "https://a.example");
+        let base_urls = config
+            .feeds
+            .iter()
+            .map(|f| f.base_url.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(base_urls, [None, Some("https://b.example"), None]);
+    }
+
+    #[test]
+    fn converts_tags() {
+        let (config, _) = convert();
+        let feed = &config.feeds[0];
+        assert_eq!(feed.url, "https://news.example/rss");
+        assert_eq!(feed.token.as_ref().map(Secret::expose), Some("t1"));
+        let tag = feed.tag.as_ref().unwrap();
+        // フィードのIDと同じタグは除く
+        assert_eq!(tag.always, ["daily"]);
+        assert_eq!(tag.ignore, ["ad"]);
+        assert_eq!(tag.replace, ["a/b"]);
+        assert_eq!(tag.xpath.as_deref(), Some("//meta"));
+        assert_eq!(tag.keywords, Some(false));
+        // タグの設定がなければ省略する
+        assert_eq!(config.feeds[1].tag, None);
+    }
+
+    #[test]
+    fn reports_unmapped_keys() {
+        let (_, unmapped) = convert();
+        assert_eq!(unmapped["interval"], Vec::<String>::new());
+        assert_eq!(unmapped["sources[].dest.account.bot"], ["news"]);
+        assert_eq!(unmapped["sources[].dest.mastodon.visibility"], ["blog"]);
+        assert_eq!(unmapped.len(), 3);
+    }
+
+    #[test]
+    fn strips_nulls() {
+        let value = serde_yaml::from_str("a: null\nb:\n  c: 1\n  d: null\ne: [null]").unwrap();
+        let expected: Value = serde_yaml::from_str("b:\n  c: 1\ne: [null]").unwrap();
+        assert_eq!(strip_nulls(value), expected);
+    }
+}
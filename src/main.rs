@@ -1,304 +1,1310 @@
+mod admin;
+mod cli;
 mod constants;
+mod control;
 mod ext_trait;
 mod feed_info;
+mod fetch_hint;
+mod fetch_log;
+mod legacy;
+mod media;
+mod metrics;
+mod post_destination;
 mod post_item;
 mod schema;
 mod setup;
+mod template;
 mod utility;
+mod yaml_path;
 
 extern crate rand;
 
 use chrono::{Duration, Utc};
 use feed_info::Entity as FeedInfo;
-use feed_rs::{model::Entry, parser as FeedParser};
-use megalodon::{megalodon::PostStatusOutput, Megalodon};
+use feed_rs::model::Entry;
+use fetch_hint::FeedHints;
+use fetch_log::Entity as FetchLog;
+use media::upload_image;
+use megalodon::{
+    megalodon::{EditStatusInputOptions, PostStatusInputOptions, PostStatusOutput},
+    Megalodon, SNS,
+};
+use post_destination::Entity as PostDestination;
 use post_item::Entity as PostItem;
 use rand::Rng;
-use reqwest;
+use regex::Regex;
 use sea_orm::{prelude::DateTimeUtc, *};
-use sentry_anyhow::capture_anyhow;
 use std::{
     collections::{HashMap, HashSet},
     env,
+    error::Error,
+    sync::Arc,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc::*, watch},
 };
-use tokio::sync::mpsc::*;
 use tokio_retry::{strategy::FixedInterval, RetryIf};
+use tracing::{error, info, info_span, warn, Instrument};
 
+use admin::AdminState;
+use clap::Parser;
+use cli::{Cli, Command};
 use constants::*;
+use control::{FeedControl, Feeds, RunningFeed};
 use ext_trait::*;
+use metrics::*;
 use schema::*;
 use setup::*;
+use template::{StatusTemplate, DEFAULT_TEMPLATE};
 use utility::*;
 
 async fn feed_loop(
     config: &FeedConfig,
+    schedule: &FeedSchedule,
     next_fetch: DateTimeUtc,
     tx: Sender<PostInfo>,
+    control: &FeedControl,
 ) -> anyhow::Result<()> {
     match next_fetch {
         date if date == DateTimeUtc::UNIX_EPOCH => {
-            let time = Duration::seconds(rand::thread_rng().gen_range(10..=60));
-            sleep(&time, &format!("init rand wait: {}", config.id)).await;
+            let time = Duration::seconds(rand::rng().random_range(10..=60));
+            control
+                .sleep(&time, &format!("init rand wait: {}", config.id))
+                .await;
         }
         next => {
             let now = Utc::now();
             if next > now {
-                sleep(&(next - now), &format!("init wait: {}", config.id)).await;
+                control
+                    .sleep(&(next - now), &format!("init wait: {}", config.id))
+                    .await;
             } else {
-                let time = Duration::seconds(rand::thread_rng().gen_range(10..=60));
-                sleep(&time, &format!("init rand wait: {}", config.id)).await;
+                let time = Duration::seconds(rand::rng().random_range(10..=60));
+                control
+                    .sleep(&time, &format!("init rand wait: {}", config.id))
+                    .await;
             }
         }
     }
     loop {
-        if let Err(err) = process_feed(config, &tx).await {
-            let id = capture_anyhow(&err);
-            println!("failed to process feed: {:?}, sentry: {}", err, id);
-            sleep(
-                &Duration::minutes(20),
-                &format!("faild wait: {}", config.id),
-            )
-            .await;
+        control.wait_resume(&config.id).await;
+        let mut log = fetch_log::ActiveModel::start(&config.id);
+        let (d, reason) = match process_feed(config, schedule, &tx, &mut log).await {
+            Ok(res) => res,
+            // 失敗のたびにSentryに送らず、停止扱いになった時だけエラーとして送る
+            Err(err) => {
+                warn!(error = error_value(&err), "failed to process feed");
+                log.error = Set(Some(format!("{:#}", err)));
+                let d = match record_failure(config, schedule, &err, &mut log).await {
+                    Ok(d) => d,
+                    Err(e) => {
+                        warn!(error = error_value(&e), "failed to record failure");
+                        Duration::minutes(20)
+                    }
+                };
+                (d, format!("failed wait: {}", config.id))
+            }
         };
+        if let Err(e) = save_fetch_log(log).await {
+            warn!(error = error_value(&e), "failed to save fetch log");
+        }
+        NEXT_FETCH_DELAY
+            .with_label_values(&[&config.id])
+            .set(d.num_seconds() as f64);
+        control.sleep(&d, &reason).await;
     }
 }
 
-async fn process_feed(config: &FeedConfig, tx: &Sender<PostInfo>) -> anyhow::Result<()> {
-    println!("check feed: {}", config.id);
+async fn save_fetch_log(log: fetch_log::ActiveModel) -> anyhow::Result<()> {
     let db = setup_connection().await?;
-    let mut info = match FeedInfo::find_by_id(&config.id).one(&db).await? {
-        Some(info) => info.into_active_model(),
-        None => {
-            let info = feed_info::Model::new(config.id.clone()).into_active_model();
-            info.insert(&db).await?.into_active_model()
-        }
+    log.insert(&db).await?;
+    db.close().await?;
+    Ok(())
+}
+
+/// 取得の失敗を記録し、次に取得するまでの待機時間を返します。
+async fn record_failure(
+    config: &FeedConfig,
+    schedule: &FeedSchedule,
+    err: &anyhow::Error,
+    log: &mut fetch_log::ActiveModel,
+) -> anyhow::Result<Duration> {
+    let db = setup_connection().await?;
+    let mut info = FeedInfo::find_or_insert(&db, &config.id).await?;
+    let (d, dead) = info.record_failure(format!("{:#}", err), schedule);
+    log.scheduled(&info);
+    let failures = *info.failures.as_ref();
+    let since = info
+        .failing_since
+        .as_ref()
+        .map(|s| s.to_rfc3339())
+        .unwrap_or_default();
+    info.save(&db).await?;
+    db.close().await?;
+    FEED_FAILURES
+        .with_label_values(&[&config.id])
+        .set(failures as i64);
+    if dead {
+        error!(feed = %config.id, error = error_value(err), failures, since, "feed is dead");
+    }
+    Ok(d)
+}
+
+/// フィードを取得して新着を投稿キューに追加し、次に取得するまでの待機時間を返します。
+async fn process_feed(
+    config: &FeedConfig,
+    schedule: &FeedSchedule,
+    tx: &Sender<PostInfo>,
+    log: &mut fetch_log::ActiveModel,
+) -> anyhow::Result<(Duration, String)> {
+    info!("check feed");
+    let db = setup_connection().await?;
+    let mut info = FeedInfo::find_or_insert(&db, &config.id).await?;
+    let timer = FETCH_DURATION
+        .with_label_values(&[&config.id])
+        .start_timer();
+    let res = info
+        .conditional_request(reqwest::Client::new().get(&config.url))
+        .send()
+        .instrument(info_span!("fetch"))
+        .await;
+    timer.observe_duration();
+    let status = match &res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
     };
-    let content = reqwest::get(&config.url).await?.bytes().await?;
-    let feed = FeedParser::Builder::new()
-        .base_uri(Some(&config.url))
-        .build()
-        .parse(content.as_ref())?;
+    FETCH_TOTAL.with_label_values(&[&config.id, &status]).inc();
+    let res = res?;
+    log.status = Set(Some(res.status().as_u16().into()));
+    // 混雑やレート制限で Retry-After が指定された場合はそれに従う
+    if matches!(res.status().as_u16(), 429 | 503) {
+        if let Some(retry_after) = fetch_hint::retry_after(res.headers()) {
+            warn!(status = res.status().as_u16(), "retry after");
+            let d = info.update_next_fetch_retry_after(retry_after, schedule);
+            log.scheduled(&info);
+            info.save(&db).await?;
+            db.close().await?;
+            return Ok((d, format!("retry after: {}", config.id)));
+        }
+    }
+    let res = res.error_for_status()?;
+    if info.record_success() {
+        info!("recovered");
+    }
+    FEED_FAILURES.with_label_values(&[&config.id]).set(0);
+    let max_age = fetch_hint::max_age(res.headers());
+    if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+        // 更新がない場合は新着なしとして待機
+        let d = info.update_next_fetch_not_modified(schedule, max_age);
+        log.scheduled(&info);
+        info.save(&db).await?;
+        db.close().await?;
+        return Ok((d, format!("not modified: {}", config.id)));
+    }
+    info.update_validators(res.headers());
+    let content = res.bytes().await?;
+    log.bytes = Set(Some(content.len() as i64));
+    let feed = info_span!("parse")
+        .in_scope(|| parse_feed(&config.url, content.as_ref()))
+        .inspect_err(|_| {
+            PARSE_FAILURES.with_label_values(&[&config.id]).inc();
+        })?;
+    match FeedHints::parse(&feed, content.as_ref()) {
+        Some(hints) => info.set_hints(&hints),
+        None => warn!("failed to read feed hints, keeping previous hints"),
+    }
+    // 投稿時に参照するので先に保存する
+    let title = feed.title.as_ref().map(|t| t.content.clone());
+    if *info.title.as_ref() != title {
+        info.title = Set(title);
+        info = info.save(&db).await?;
+    }
 
-    // 最新の投稿を取得
-    let Some(entry) = feed
+    let entries = feed
         .entries
         .iter()
-        .filter(|e| e.title.is_some() && e.links.len() > 0)
-        .max_by_key(|e| e.pub_date_utc().unwrap())
-    else {
-        // 1番目の記事が存在しない場合は待機
-        let d = info.update_next_fetch(&feed);
+        .filter(|e| e.title.is_some() && !e.links.is_empty())
+        .collect::<Vec<_>>();
+    log.entries = Set(Some(entries.len() as i32));
+    if entries.is_empty() {
+        // 記事が存在しない場合は待機
+        let d = info.update_next_fetch(&feed, schedule, max_age);
+        log.scheduled(&info);
         info.save(&db).await?;
-        sleep(&d, &format!("not found: {}", config.id)).await;
-        return Ok(());
-    };
+        return Ok((d, format!("not found: {}", config.id)));
+    }
 
-    // キューに追加済みの最新の投稿を取得
-    let last_post = PostItem::find()
-        .filter(post_item::Column::Source.eq(&config.id))
-        .order_by_desc(post_item::Column::PubDate)
-        .one(&db)
-        .await?;
+    // 旧バージョンの登録分は最初の取得でGUIDを補う
+    let backfilled = PostItem::backfill_guids(&db, &config.id, &entries).await?;
+    if backfilled > 0 {
+        info!(backfilled, "backfilled guids");
+    }
+
+    // 登録済みの直近の投稿を取得
+    let recent = PostItem::find_recent(&db, &config.id).await?;
 
     // 初回は投稿せずに登録のみ
-    let Some(last_post) = last_post else {
-        PostItem::insert(&db, &config.id, entry).await?;
-        let d = info.update_next_fetch(&feed);
+    if recent.is_empty() {
+        for entry in entries {
+            PostItem::insert(&db, &config.id, entry).await?;
+        }
+        let d = info.update_next_fetch(&feed, schedule, max_age);
+        log.scheduled(&info);
         info.save(&db).await?;
-        sleep(&d, &format!("first wait: {}", config.id)).await;
-        return Ok(());
-    };
+        return Ok((d, format!("first wait: {}", config.id)));
+    }
 
-    if feed.entries.iter().any(|e| e.published == None) {
-        // atom 0.3 は published がないので、last_posted と比較する
-        let entry = feed.entries.get(0).unwrap();
-        let title = &entry.title.as_ref().unwrap().content;
-        let link = &entry.links.get(0).unwrap().href;
-        if last_post.title != *title || last_post.link != *link {
-            let post = PostItem::insert(&db, &config.id, entry).await?;
-            tx.send(PostInfo(post.id, entry.clone(), config.clone()))
-                .await?;
-            sleep(&QUEUE_INTERVAL, &format!("queue wait : {}", config.id)).await;
+    // 投稿済みの記事のタイトルやリンクが変わっていれば設定に合わせて編集または再投稿する
+    let on_update = config.on_update.as_ref().unwrap_or(&UpdateMode::Off);
+    if *on_update != UpdateMode::Off {
+        let mut latest = HashMap::new();
+        for p in &recent {
+            if let Some(guid) = &p.guid {
+                // 新しい順なので最初の登録が最新
+                latest.entry(guid.as_str()).or_insert(p);
+            }
         }
-    } else {
-        // 前回投稿日時以降の記事を投稿する
-        let mut entries: Vec<_> = feed
-            .entries
+        for entry in &entries {
+            let Some(p) = latest.get(entry.id.as_str()) else {
+                continue;
+            };
+            let changed = |title: &str, link: &str| {
+                entry.title.as_ref().map(|t| t.content.as_str()) != Some(title)
+                    || entry.normalized_link() != link
+            };
+            if p.post_id.is_none() || !changed(&p.title, &p.link) {
+                continue;
+            }
+            // 編集待ちと同じ内容なら再試行に任せる
+            if let Some(pending) = p.pending_edit().ok().flatten() {
+                let title = pending.title.as_ref().map(|t| t.content.as_str());
+                if !changed(title.unwrap_or_default(), &pending.normalized_link()) {
+                    continue;
+                }
+            }
+            info!(guid = %entry.id, "updated");
+            let (id, kind) = match on_update {
+                UpdateMode::Edit => {
+                    // タイトルとリンクは全ての投稿先で編集できてから更新する
+                    PostItem::queue_edit(&db, p.id, entry).await?;
+                    (p.id, PostKind::Edit((*entry).clone()))
+                }
+                _ => {
+                    let post = PostItem::enqueue(&db, &config.id, entry).await?;
+                    update_queue_depth(&db).await;
+                    (post.id, PostKind::New((*entry).clone()))
+                }
+            };
+            tx.send(PostInfo(id, config.clone(), kind)).await?;
+            sleep(
+                &schedule.queue_interval,
+                &format!("queue wait : {}", config.id),
+            )
+            .await;
+        }
+    }
+
+    let now = Utc::now();
+    // 投稿済みの記事がフィードから消え、リンク先も削除されていれば取り下げとみなす
+    let on_retract = config.on_retract.as_ref().unwrap_or(&RetractMode::Off);
+    if *on_retract != RetractMode::Off {
+        let seen = entries.iter().map(|e| e.id.clone()).collect::<HashSet<_>>();
+        // 古い記事がフィードから押し出されただけの場合は除く
+        let oldest = entries.iter().map(|e| *e.pub_date_utc_or(&now)).min();
+        let missing = recent
             .iter()
-            .filter(|e| e.pub_date_utc().unwrap() > &last_post.pub_date)
-            .collect();
-        // 公開日時でソートする
-        entries.sort_by_key(|e| e.pub_date_utc().unwrap());
-        for entry in entries {
-            let post = PostItem::insert(&db, &config.id, entry).await?;
-            tx.send(PostInfo(post.id, entry.clone(), config.clone()))
+            .filter(|p| {
+                p.post_id.is_some()
+                    && p.last_seen.is_some()
+                    && !p.retract_pending
+                    && !p.retracted
+                    && p.guid.as_ref().is_some_and(|g| !seen.contains(g))
+                    && oldest.is_some_and(|o| p.pub_date >= o)
+            })
+            .collect::<Vec<_>>();
+        PostItem::add_missing(&db, missing.iter().map(|p| p.id).collect()).await?;
+        for p in missing {
+            // 一時的にフィードから外れただけの場合もあるので、連続して見つからなかった場合だけ確認する
+            if (p.missing + 1) % *RETRACT_CHECK_MISSES != 0 || !is_gone(&p.link).await {
+                continue;
+            }
+            info!(link = %p.link, "retracted");
+            // 取り下げ済みの記録は全ての投稿先で削除や返信ができてから行う
+            PostItem::queue_retract(&db, p.id).await?;
+            tx.send(PostInfo(p.id, config.clone(), PostKind::Retract))
                 .await?;
-            sleep(&QUEUE_INTERVAL, &format!("queue wait : {}", config.id)).await;
+            sleep(
+                &schedule.queue_interval,
+                &format!("queue wait : {}", config.id),
+            )
+            .await;
         }
+        PostItem::update_last_seen(&db, &config.id, seen).await?;
     }
 
-    let d = info.update_next_fetch(&feed);
+    let entries = find_new_entries(&recent, entries);
+    log.found(
+        &entries
+            .iter()
+            .filter_map(|e| e.pub_date_utc().copied())
+            .collect::<Vec<_>>(),
+    );
+    for entry in entries {
+        let post = PostItem::enqueue(&db, &config.id, entry).await?;
+        ENTRIES_DISCOVERED.with_label_values(&[&config.id]).inc();
+        update_queue_depth(&db).await;
+        tx.send(PostInfo(
+            post.id,
+            config.clone(),
+            PostKind::New(entry.clone()),
+        ))
+        .await?;
+        sleep(
+            &schedule.queue_interval,
+            &format!("queue wait : {}", config.id),
+        )
+        .await;
+    }
+
+    let d = info.update_next_fetch(&feed, schedule, max_age);
+    log.scheduled(&info);
     info.update(&db).await?;
     db.close().await?;
-    sleep(&d, &format!("check wait: {}", config.id)).await;
-    Ok(())
+    Ok((d, format!("check wait: {}", config.id)))
+}
+
+/// 登録済みの記事と比較して新着の記事を古い順に返します。
+/// GUIDのある記事はGUIDが登録されていない記事を新着とします。
+/// リンクから生成したIDの記事は、リンクも登録されておらず、
+/// タイトルと公開日時が一致する記事も登録されていない記事を新着とします。
+fn find_new_entries<'a>(recent: &[post_item::Model], entries: Vec<&'a Entry>) -> Vec<&'a Entry> {
+    let now = Utc::now();
+    let mut guids = recent
+        .iter()
+        .filter_map(|p| p.guid.clone())
+        .collect::<HashSet<_>>();
+    let mut links = recent
+        .iter()
+        .map(|p| normalize_link(&p.link))
+        .collect::<HashSet<_>>();
+    let mut dated_titles = recent
+        .iter()
+        .map(|p| (p.title.clone(), p.pub_date))
+        .collect::<HashSet<_>>();
+    let mut entries = entries
+        .into_iter()
+        .filter(|e| {
+            // 同じフィード内の重複も除くため、全て登録してから判定する
+            let link = e.normalized_link();
+            let generated = e.id == link;
+            let new_guid = guids.insert(e.id.clone());
+            let new_link = links.insert(link);
+            // GUIDが違えばリンクやタイトルが同じでも別の記事として扱う
+            if !generated {
+                return new_guid;
+            }
+            let new_title = e
+                .title
+                .as_ref()
+                .zip(e.pub_date_utc())
+                .is_none_or(|(t, d)| dated_titles.insert((t.content.clone(), *d)));
+            new_guid && new_link && new_title
+        })
+        .collect::<Vec<_>>();
+    // フィードは新しい順に並んでいることが多いので、逆順にしてから公開日時で安定ソートする
+    entries.reverse();
+    entries.sort_by_key(|e| *e.pub_date_utc_or(&now));
+    entries
+}
+
+struct PostInfo(i32, FeedConfig, PostKind);
+
+enum PostKind {
+    /// 新着として投稿する
+    New(Entry),
+    /// 投稿済みのステータスを編集する
+    Edit(Entry),
+    /// 取り下げられた記事の投稿を削除または返信で通知する
+    Retract,
 }
 
-struct PostInfo(i32, Entry, FeedConfig);
+/// 投稿先ごとのクライアントとインスタンス情報のキャッシュ
+#[derive(Default)]
+struct Clients {
+    /// サーバー、トークン、SNSごとのクライアント (再判定でSNSが変わった場合は作り直す)
+    cache: HashMap<(String, String, String), Box<dyn Megalodon + Send + Sync>>,
+    /// 判定したSNS。判定に失敗した場合は再判定する日時と合わせて保存する
+    detected: HashMap<String, (SNS, Option<DateTimeUtc>)>,
+    limits: HashMap<String, StatusLimit>,
+}
+
+impl Clients {
+    /// 投稿先のSNS、文字数制限、クライアントを取得します。
+    async fn get(
+        &mut self,
+        global: &Config,
+        config: &FeedConfig,
+        dest: &DestinationConfig,
+    ) -> (SNS, StatusLimit, &(dyn Megalodon + Send + Sync)) {
+        let server = global.server(config, dest).to_string();
+        let sns = match dest
+            .sns
+            .as_ref()
+            .or(config.sns.as_ref())
+            .or(global.sns.as_ref())
+        {
+            Some(sns) => sns.into(),
+            None => detect_sns(&mut self.detected, &server).await,
+        };
+        let client = &*self
+            .cache
+            .entry((
+                server.clone(),
+                dest.token.expose().to_string(),
+                sns.to_string(),
+            ))
+            .or_insert_with(|| {
+                megalodon::generator(
+                    sns.clone(),
+                    server.clone(),
+                    Some(dest.token.expose().to_string()),
+                    None,
+                )
+            });
+        let limit = get_status_limit(&mut self.limits, client.as_ref(), &server, &sns).await;
+        (sns, limit, client.as_ref())
+    }
+}
 
 async fn post_loop(
+    db: &DatabaseConnection,
     mut rx: Receiver<PostInfo>,
-    base_url: &String,
-    tag: &Option<TagConfig>,
+    global: watch::Receiver<Arc<Config>>,
+    feeds: Feeds,
     is_dry_run: &bool,
+    mut shutdown: watch::Receiver<bool>,
 ) {
-    let db = setup_connection().await.unwrap();
-    let mut cache = HashMap::new();
-    while let Some(PostInfo(id, entry, config)) = rx.recv().await {
-        println!("Got: {:?}", entry);
-        let client = cache.entry(config.url.clone()).or_insert_with(|| {
-            megalodon::generator(
-                megalodon::SNS::Mastodon,
-                base_url.clone(),
-                Some(config.token.clone()),
-                None,
-            )
-        });
-        let posted_id = RetryIf::spawn(
-            FixedInterval::from_millis(5000).take(2),
-            || async { post(&client, &config, &tag, &entry, is_dry_run).await },
-            |e: &anyhow::Error| {
-                if let Some(e) = e.downcast_ref::<megalodon::error::Error>() {
-                    if let megalodon::error::Error::OwnError(e) = e {
-                        if let Some(s) = e.status {
-                            if s == 429 {
-                                println!("retry: {}", config.id);
-                                return true;
-                            }
-                        }
-                    }
-                }
-                false
+    let mut clients = Clients::default();
+    let mut retry = tokio::time::interval(RETRY_INTERVAL.to_std().unwrap());
+    retry.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // 最初の tick はすぐに返るので読み捨てる (起動時のキューは restore_queue で復元する)
+    retry.tick().await;
+    'main: loop {
+        // 投稿中の記事は最後まで投稿し、待機中であればすぐに終了する
+        let infos = tokio::select! {
+            _ = shutdown.wait_for(|s| *s) => break,
+            info = rx.recv() => match info {
+                Some(info) => vec![info],
+                None => break,
             },
-        )
-        .await;
-        if let Err(e) = posted_id {
-            let id = capture_anyhow(&e);
-            println!("failed to post: {:?}, sentry: {}", e, id);
-            sleep(
-                &Duration::seconds(10),
-                &format!("failed post: {}", config.id),
-            )
-            .await;
-            continue;
+            _ = retry.tick() => find_retries(db, &feeds).await,
+        };
+        for PostInfo(id, config, kind) in infos {
+            // キューに追加した後に設定が再読み込みされていれば新しい設定で投稿する
+            let global = global.borrow().clone();
+            let config = match feeds.lock().await.get(&config.id) {
+                Some(running) => running.config.clone(),
+                None => config,
+            };
+            let span = info_span!("post_item", feed = %config.id, post_id = id);
+            let wait = process_post(db, &mut clients, &global, &config, id, kind, is_dry_run)
+                .instrument(span.clone())
+                .await;
+            if !wait.is_zero() {
+                let reason = format!("post wait: {}", config.id);
+                tokio::select! {
+                    _ = shutdown.wait_for(|s| *s) => break 'main,
+                    _ = sleep(&wait, &reason).instrument(span) => {}
+                }
+            }
         }
-        let posted_id = posted_id.unwrap();
-        if let Err(e) = PostItem::update(post_item::ActiveModel {
-            id: Set(id),
-            post_id: Set(Some(posted_id)),
-            ..Default::default()
-        })
-        .exec(&db)
-        .await
-        {
-            let id = capture_anyhow(&anyhow::anyhow!(format!("failed: {:?}", e)));
-            println!("failed to update post id: {:?}, sentry: {}", e, id);
+    }
+    release_pending(db, &mut rx).await;
+    info!("post loop stopped");
+}
+
+/// 投稿に失敗したキューをDBから読み込みます。実行中でないフィードのキューは除きます。
+async fn find_retries(db: &DatabaseConnection, feeds: &Feeds) -> Vec<PostInfo> {
+    let items = match PostItem::find_retry().all(db).await {
+        Ok(items) => items,
+        Err(e) => {
+            warn!(error = &e as &dyn Error, "failed to find retries");
+            return vec![];
+        }
+    };
+    let edits = match PostItem::find_edit_retry().all(db).await {
+        Ok(edits) => edits,
+        Err(e) => {
+            warn!(error = &e as &dyn Error, "failed to find edit retries");
+            vec![]
+        }
+    };
+    let retracts = match PostItem::find_retract_retry().all(db).await {
+        Ok(retracts) => retracts,
+        Err(e) => {
+            warn!(error = &e as &dyn Error, "failed to find retract retries");
+            vec![]
         }
+    };
+    let feeds = feeds.lock().await;
+    let infos = items
+        .into_iter()
+        .filter_map(|post| {
+            let config = feeds.get(&post.source)?.config.clone();
+            let entry = post.entry().ok()??;
+            Some(PostInfo(post.id, config, PostKind::New(entry)))
+        })
+        .chain(edits.into_iter().filter_map(|post| {
+            let config = feeds.get(&post.source)?.config.clone();
+            let entry = post.pending_edit().ok()??;
+            Some(PostInfo(post.id, config, PostKind::Edit(entry)))
+        }))
+        .chain(retracts.into_iter().filter_map(|post| {
+            let config = feeds.get(&post.source)?.config.clone();
+            Some(PostInfo(post.id, config, PostKind::Retract))
+        }))
+        .collect::<Vec<_>>();
+    if !infos.is_empty() {
+        info!(count = infos.len(), "retry queued items");
+    }
+    infos
+}
 
-        if let Ok(queue_count) = PostItem::find()
-            .filter(post_item::Column::PostId.is_null())
-            .count(&db)
-            .await
-        {
-            if let Ok(feed_count) = FeedInfo::find().count(&db).await {
-                println!("queue count: {}", queue_count - feed_count);
-            } else {
-                println!("failed to count feed");
+/// 終了時に投稿されなかったキューの件数を記録します。
+/// 新着、編集、取り下げはいずれもDBに残っているので次回の起動時に復元されます。
+/// フィードの取得が止まって送信側が全て閉じてから数えるので、取りこぼしはありません。
+async fn release_pending(db: &DatabaseConnection, rx: &mut Receiver<PostInfo>) {
+    let mut pending = 0;
+    while rx.recv().await.is_some() {
+        pending += 1;
+    }
+    match PostItem::find_queued().count(db).await {
+        Ok(queued) => info!(pending, queued, "pending items are kept in the queue"),
+        Err(e) => warn!(error = &e as &dyn Error, "failed to count queue"),
+    }
+}
+
+/// キューの記事を投稿し、次の投稿までの待機時間を返します。
+async fn process_post(
+    db: &DatabaseConnection,
+    clients: &mut Clients,
+    global: &Config,
+    config: &FeedConfig,
+    id: i32,
+    kind: PostKind,
+    is_dry_run: &bool,
+) -> Duration {
+    let entry = match kind {
+        PostKind::New(entry) => entry,
+        PostKind::Edit(entry) => {
+            info!(entry = ?entry, "got edit");
+            // 再試行までに編集済みになった記事は編集しない
+            match PostItem::find_by_id(id).one(db).await {
+                Ok(Some(item)) if item.pending_edit.is_none() => {
+                    info!("already edited");
+                    return Duration::zero();
+                }
+                Ok(_) => {}
+                Err(e) => warn!(error = &e as &dyn Error, "failed to find post item"),
+            }
+            if !edit_item(db, clients, global, config, id, &entry, is_dry_run).await {
+                if let Err(e) = PostItem::add_attempt(db, id).await {
+                    warn!(error = error_value(&e), "failed to update attempts");
+                }
+                return Duration::seconds(10);
             }
-        } else {
-            println!("failed to count queue");
+            if let Err(e) = PostItem::finish_edit(db, id, &entry).await {
+                error!(error = error_value(&e), "failed to update post item");
+            }
+            return global.post_interval();
         }
-        sleep(&POST_INTERVAL, &format!("post wait: {}", config.id)).await;
+        PostKind::Retract => {
+            info!("got retract");
+            // 再試行までに取り下げ済みになった記事は処理しない
+            match PostItem::find_by_id(id).one(db).await {
+                Ok(Some(item)) if item.retracted || !item.retract_pending => {
+                    info!("already retracted");
+                    return Duration::zero();
+                }
+                Ok(_) => {}
+                Err(e) => warn!(error = &e as &dyn Error, "failed to find post item"),
+            }
+            if !retract_item(db, clients, global, config, id, is_dry_run).await {
+                if let Err(e) = PostItem::add_attempt(db, id).await {
+                    warn!(error = error_value(&e), "failed to update attempts");
+                }
+                return Duration::seconds(10);
+            }
+            if let Err(e) = PostItem::mark_retracted(db, id).await {
+                error!(error = error_value(&e), "failed to mark retracted");
+            }
+            return global.post_interval();
+        }
+    };
+    info!(entry = ?entry, "got");
+    // 管理APIから取り消されたキューと、再試行までに投稿済みになったキューは投稿しない
+    match PostItem::find_by_id(id).one(db).await {
+        Ok(Some(item)) if item.entry.is_none() => {
+            info!("dropped");
+            return Duration::zero();
+        }
+        Ok(Some(item)) if item.post_id.is_some() => {
+            info!("already posted");
+            return Duration::zero();
+        }
+        Ok(_) => {}
+        Err(e) => warn!(error = &e as &dyn Error, "failed to find post item"),
     }
+    // 投稿済みの投稿先には重複して投稿しない
+    let posted = match PostDestination::find_by_item(db, id).await {
+        Ok(posted) => posted,
+        Err(e) => {
+            error!(
+                error = error_value(&e),
+                "failed to find posted destinations"
+            );
+            return Duration::zero();
+        }
+    };
+    let feed_title = match FeedInfo::find_by_id(&config.id).one(db).await {
+        Ok(info) => info.and_then(|i| i.title),
+        Err(e) => {
+            warn!(error = &e as &dyn Error, "failed to find feed info");
+            None
+        }
+    };
+    let mut first_id = posted.first().map(|p| p.post_id.clone());
+    let mut failed = false;
+    for dest in config.destinations() {
+        if posted.iter().any(|p| p.destination == dest.id) {
+            continue;
+        }
+        let (sns, limit, client) = clients.get(global, config, &dest).await;
+        let posted_id =
+            match render_status(&sns, &limit, global, config, &dest, &feed_title, &entry)
+                .instrument(info_span!("tag", destination = %dest.id))
+                .await
+            {
+                Ok(status) => {
+                    let now = Utc::now();
+                    let pud_date = entry.pub_date_utc_or(&now);
+                    info!(
+                        destination = %dest.id,
+                        pub_date = %pud_date.to_rfc3339(),
+                        lag = %(now - pud_date).to_iso8601(),
+                        "posting"
+                    );
+                    let options = status_config(global, config, &dest);
+                    let media = config.media.as_ref().or(global.media.as_ref());
+                    let server = global.server(config, &dest);
+                    RetryIf::start(
+                        FixedInterval::from_millis(5000).take(2),
+                        || async {
+                            post(client, server, &dest, &status, &options, media, is_dry_run).await
+                        },
+                        |e: &anyhow::Error| {
+                            if let Some(megalodon::error::Error::OwnError(e)) =
+                                e.downcast_ref::<megalodon::error::Error>()
+                            {
+                                if e.status == Some(429) {
+                                    warn!(destination = %dest.id, "retry");
+                                    POST_RETRIES
+                                        .with_label_values(&[&config.id, &dest.id])
+                                        .inc();
+                                    return true;
+                                }
+                            }
+                            false
+                        },
+                    )
+                    .instrument(info_span!("post", destination = %dest.id))
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+        match posted_id {
+            Ok((posted_id, media_ids)) => {
+                POSTS_SUCCEEDED
+                    .with_label_values(&[&config.id, &dest.id])
+                    .inc();
+                let now = Utc::now();
+                POST_LAG
+                    .with_label_values(&[&config.id])
+                    .observe((now - *entry.pub_date_utc_or(&now)).num_seconds() as f64);
+                if let Err(e) =
+                    PostDestination::insert(db, id, &dest.id, &posted_id, &media_ids).await
+                {
+                    error!(
+                        error = error_value(&e),
+                        destination = %dest.id,
+                        "failed to insert post destination"
+                    );
+                }
+                first_id.get_or_insert(posted_id);
+            }
+            Err(e) => {
+                failed = true;
+                POSTS_FAILED
+                    .with_label_values(&[&config.id, &dest.id, error_class(&e)])
+                    .inc();
+                error!(error = error_value(&e), destination = %dest.id, "failed to post");
+            }
+        }
+    }
+    if failed {
+        // 失敗した投稿先だけ RETRY_INTERVAL ごとに再試行できるよう試行回数を記録
+        if let Err(e) = PostItem::add_attempt(db, id).await {
+            warn!(error = error_value(&e), "failed to update attempts");
+        }
+        // 試行回数の上限に達したキューは数えない
+        update_queue_depth(db).await;
+        return Duration::seconds(10);
+    }
+    if let Err(e) = PostItem::update(post_item::ActiveModel {
+        id: Set(id),
+        post_id: Set(Some(first_id.unwrap_or_default())),
+        ..Default::default()
+    })
+    .exec(db)
+    .await
+    {
+        error!(error = &e as &dyn Error, "failed to update post id");
+    }
+
+    update_queue_depth(db).await;
+    global.post_interval()
 }
 
-async fn post(
-    client: &Box<dyn Megalodon + Send + Sync>,
+/// 投稿先のSNSを判定します。判定できなかった場合は SNS_DETECT_TTL の間Mastodonとして扱います。
+async fn detect_sns(
+    detected: &mut HashMap<String, (SNS, Option<DateTimeUtc>)>,
+    server: &str,
+) -> SNS {
+    if let Some((sns, retry_at)) = detected.get(server) {
+        if retry_at.is_none_or(|r| Utc::now() < r) {
+            return sns.clone();
+        }
+    }
+    match megalodon::detector(server.trim_end_matches('/')).await {
+        Ok(sns) => {
+            info!(server, sns = %sns, "detected sns");
+            detected.insert(server.to_string(), (sns.clone(), None));
+            sns
+        }
+        Err(e) => {
+            warn!(server, error = &e as &dyn Error, "failed to detect sns");
+            let retry_at = Utc::now() + *SNS_DETECT_TTL;
+            detected.insert(server.to_string(), (SNS::Mastodon, Some(retry_at)));
+            SNS::Mastodon
+        }
+    }
+}
+
+/// リンク先が削除されているか (404 または 410) を確認します。
+async fn is_gone(link: &str) -> bool {
+    match reqwest::Client::new().head(link).send().await {
+        Ok(res) => matches!(
+            res.status(),
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE
+        ),
+        Err(e) => {
+            warn!(link, error = &e as &dyn Error, "failed to check link");
+            false
+        }
+    }
+}
+
+/// 投稿先の設定に合わせて投稿内容を作成します。
+async fn render_status(
+    sns: &SNS,
+    limit: &StatusLimit,
+    global: &Config,
     config: &FeedConfig,
-    global_tag: &Option<TagConfig>,
+    dest: &DestinationConfig,
+    feed_title: &Option<String>,
     entry: &Entry,
-    is_dry_run: &bool,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<StatusContent> {
     let mut merged_tag = TagConfig::new();
-    if let Some(tag) = global_tag {
-        merged_tag.always.extend(tag.always.clone());
-        merged_tag.ignore.extend(tag.ignore.clone());
-        merged_tag.replace.extend(tag.replace.clone());
-        merged_tag.xpath = tag.xpath.clone();
-        merged_tag.keywords = tag.keywords.clone();
-    }
-    if let Some(tag) = &config.tag {
-        merged_tag.always.extend(tag.always.clone());
-        merged_tag.ignore.extend(tag.ignore.clone());
-        merged_tag.replace.extend(tag.replace.clone());
-        merged_tag.xpath = tag.xpath.clone();
-        merged_tag.keywords = tag.keywords.clone();
-    }
-    let status = entry.to_status(config.id.clone(), &merged_tag).await?;
-    let now = Utc::now();
-    let pud_date = entry.pub_date_utc_or(&now);
-    println!(
-        "source: {}, pub: {} rag: {}",
-        config.id,
-        pud_date.to_rfc3339(),
-        (now - pud_date).to_iso8601()
-    );
+    for tag in [&global.tag, &config.tag, &dest.tag].into_iter().flatten() {
+        merged_tag.merge(tag);
+    }
+    let text = config
+        .template
+        .as_deref()
+        .or(global.template.as_deref())
+        .unwrap_or(DEFAULT_TEMPLATE);
+    // タイトルが一致するルールがあればそのCWを優先する
+    let options = status_config(global, config, dest);
+    let title = entry.title.as_ref().map(|t| t.content.as_str());
+    let spoiler_text = options
+        .cw_rules
+        .iter()
+        .find(|r| {
+            Regex::new(&r.pattern)
+                .ok()
+                .zip(title)
+                .is_some_and(|(re, title)| re.is_match(title))
+        })
+        .map(|r| r.spoiler_text.as_str())
+        .or(options.spoiler_text.as_deref());
+    entry
+        .to_status(
+            config.id.clone(),
+            feed_title.clone(),
+            &merged_tag,
+            sns,
+            StatusTemplate { text, spoiler_text },
+            limit,
+        )
+        .await
+}
+
+/// 全体、フィード、投稿先の順に公開範囲やCWの設定を重ねます。
+fn status_config(global: &Config, config: &FeedConfig, dest: &DestinationConfig) -> StatusConfig {
+    let mut merged = global.status.clone();
+    merged.merge(&config.status);
+    if dest.visibility.is_some() {
+        merged.visibility = dest.visibility.clone();
+    }
+    merged
+}
+
+/// インスタンスの文字数制限を取得します。取得できなかった場合はSNSごとの既定値を使用します。
+async fn get_status_limit(
+    limits: &mut HashMap<String, StatusLimit>,
+    client: &(dyn Megalodon + Send + Sync),
+    server: &str,
+    sns: &SNS,
+) -> StatusLimit {
+    if let Some(limit) = limits.get(server) {
+        return limit.clone();
+    }
+    match client.get_instance().await {
+        Ok(res) => {
+            let statuses = res.json().configuration.statuses;
+            let limit = StatusLimit {
+                max_characters: statuses.max_characters as usize,
+                characters_reserved_per_url: statuses
+                    .characters_reserved_per_url
+                    .map(|c| c as usize),
+            };
+            limits.insert(server.to_string(), limit.clone());
+            limit
+        }
+        Err(e) => {
+            warn!(server, error = &e as &dyn Error, "failed to get instance");
+            sns.default_limit()
+        }
+    }
+}
+
+async fn post(
+    client: &(dyn Megalodon + Send + Sync),
+    server: &str,
+    dest: &DestinationConfig,
+    status: &StatusContent,
+    options: &StatusConfig,
+    media: Option<&MediaConfig>,
+    is_dry_run: &bool,
+) -> anyhow::Result<(String, Vec<String>)> {
+    // 画像の添付が有効な場合のみ
+    let image = media.zip(status.image.as_ref());
     if *is_dry_run {
-        println!("dry run");
-        Ok("".to_string())
+        if let Some(spoiler_text) = &status.spoiler_text {
+            info!(spoiler_text, "dry run cw");
+        }
+        info!(text = %status.text, "dry run");
+        if let Some((_, image)) = image {
+            info!(url = %image.url, alt = ?image.alt, "dry run image");
+        }
+        Ok(("".to_string(), vec![]))
     } else {
-        let res = client.post_status(status, None).await?;
+        let mut media_ids = None;
+        if let Some((media, image)) = image {
+            // 画像が添付できなくても本文は投稿する
+            match upload_image(client, server, dest.token.expose(), media, image).await {
+                Ok(media_id) => media_ids = Some(vec![media_id]),
+                Err(e) => {
+                    warn!(url = %image.url, error = error_value(&e), "failed to upload image")
+                }
+            }
+        }
+        let options = PostStatusInputOptions {
+            media_ids,
+            sensitive: options.sensitive,
+            spoiler_text: status.spoiler_text.clone(),
+            visibility: options.visibility.clone(),
+            language: options.language.clone(),
+            ..Default::default()
+        };
+        let res = client
+            .post_status(status.text.clone(), Some(&options))
+            .await?;
         let PostStatusOutput::Status(status) = res.json() else {
             return Err(anyhow::anyhow!(format!(
                 "failed expected response: {:?}",
                 res
             )));
         };
-        Ok(status.id)
+        let media_ids = status.media_attachments.into_iter().map(|m| m.id).collect();
+        Ok((status.id, media_ids))
     }
 }
 
-async fn config_reload_loop(tx: Sender<PostInfo>) -> anyhow::Result<()> {
-    let mut feeds = HashSet::new();
+/// 更新された記事の内容で投稿済みのステータスを編集し、全ての投稿先で編集できたかを返します。
+/// 編集は同じ内容で繰り返しても問題ないため、再試行では全ての投稿先を編集し直します。
+async fn edit_item(
+    db: &DatabaseConnection,
+    clients: &mut Clients,
+    global: &Config,
+    config: &FeedConfig,
+    id: i32,
+    entry: &Entry,
+    is_dry_run: &bool,
+) -> bool {
+    let posted = match PostDestination::find_by_item(db, id).await {
+        Ok(posted) => posted,
+        Err(e) => {
+            error!(
+                error = error_value(&e),
+                "failed to find posted destinations"
+            );
+            return false;
+        }
+    };
+    let feed_title = match FeedInfo::find_by_id(&config.id).one(db).await {
+        Ok(info) => info.and_then(|i| i.title),
+        Err(e) => {
+            warn!(error = &e as &dyn Error, "failed to find feed info");
+            None
+        }
+    };
+    let destinations = config.destinations();
+    let mut succeeded = true;
+    for p in posted {
+        // 設定から削除された投稿先は編集しない
+        let Some(dest) = destinations.iter().find(|d| d.id == p.destination) else {
+            continue;
+        };
+        let (sns, limit, client) = clients.get(global, config, dest).await;
+        let result =
+            match render_status(&sns, &limit, global, config, dest, &feed_title, entry).await {
+                Ok(status) => match p.media_ids() {
+                    Ok(media_ids) => {
+                        let options = status_config(global, config, dest);
+                        edit(client, &p.post_id, &status, &options, media_ids, is_dry_run).await
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+        if let Err(e) = result {
+            succeeded = false;
+            error!(error = error_value(&e), destination = %dest.id, "failed to edit");
+        }
+    }
+    succeeded
+}
+
+async fn edit(
+    client: &(dyn Megalodon + Send + Sync),
+    post_id: &str,
+    status: &StatusContent,
+    options: &StatusConfig,
+    media_ids: Vec<String>,
+    is_dry_run: &bool,
+) -> anyhow::Result<()> {
+    if *is_dry_run || post_id.is_empty() {
+        info!(post_id, text = %status.text, "dry run edit");
+        return Ok(());
+    }
+    // 編集では送らなかったメディアが外れるため、添付した画像を送り直す
+    let options = EditStatusInputOptions {
+        status: Some(status.text.clone()),
+        spoiler_text: status.spoiler_text.clone(),
+        sensitive: options.sensitive,
+        language: options.language.clone(),
+        media_ids: (!media_ids.is_empty()).then_some(media_ids),
+        ..Default::default()
+    };
+    client.edit_status(post_id.to_string(), &options).await?;
+    Ok(())
+}
+
+/// 取り下げられた記事の投稿を削除するか、取り下げを返信で通知し、全ての投稿先で済んだかを返します。
+/// 済んだ投稿先は記録し、再試行では残りの投稿先だけを処理します。
+async fn retract_item(
+    db: &DatabaseConnection,
+    clients: &mut Clients,
+    global: &Config,
+    config: &FeedConfig,
+    id: i32,
+    is_dry_run: &bool,
+) -> bool {
+    let posted = match PostDestination::find_by_item(db, id).await {
+        Ok(posted) => posted,
+        Err(e) => {
+            error!(
+                error = error_value(&e),
+                "failed to find posted destinations"
+            );
+            return false;
+        }
+    };
+    let mode = config.on_retract.as_ref().unwrap_or(&RetractMode::Off);
+    let note = config
+        .retract_note
+        .as_deref()
+        .unwrap_or(DEFAULT_RETRACT_NOTE);
+    let destinations = config.destinations();
+    let mut succeeded = true;
+    for p in posted.iter().filter(|p| !p.retracted) {
+        let Some(dest) = destinations.iter().find(|d| d.id == p.destination) else {
+            continue;
+        };
+        let (_, _, client) = clients.get(global, config, dest).await;
+        let options = status_config(global, config, dest);
+        if let Err(e) = retract(client, &p.post_id, mode, note, &options, is_dry_run).await {
+            succeeded = false;
+            error!(error = error_value(&e), destination = %dest.id, "failed to retract");
+            continue;
+        }
+        if let Err(e) = PostDestination::mark_retracted(db, p.id).await {
+            // 記録できなければ再試行で重複して返信しないよう全体も取り下げ済みにしない
+            succeeded = false;
+            error!(
+                error = error_value(&e),
+                destination = %dest.id,
+                "failed to mark retracted"
+            );
+        }
+    }
+    succeeded
+}
+
+async fn retract(
+    client: &(dyn Megalodon + Send + Sync),
+    post_id: &str,
+    mode: &RetractMode,
+    note: &str,
+    options: &StatusConfig,
+    is_dry_run: &bool,
+) -> anyhow::Result<()> {
+    if *is_dry_run || post_id.is_empty() {
+        info!(post_id, mode = ?mode, "dry run retract");
+        return Ok(());
+    }
+    match mode {
+        RetractMode::Off => {}
+        RetractMode::Delete => {
+            client.delete_status(post_id.to_string()).await?;
+        }
+        RetractMode::Reply => {
+            let options = PostStatusInputOptions {
+                in_reply_to_id: Some(post_id.to_string()),
+                visibility: options.visibility.clone(),
+                language: options.language.clone(),
+                ..Default::default()
+            };
+            client.post_status(note.to_string(), Some(&options)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 前回の起動時に投稿されなかったキューと、編集や取り下げを待っている記事を復元します。
+async fn restore_queue(config: &Config, tx: &Sender<PostInfo>) -> anyhow::Result<()> {
+    let db = setup_connection().await?;
+    let feeds = config
+        .feeds
+        .iter()
+        .map(|f| (&f.id, f))
+        .collect::<HashMap<_, _>>();
+    for post in PostItem::find_queued().all(&db).await? {
+        // 設定から削除されたフィードは投稿しない
+        let Some(feed) = feeds.get(&post.source) else {
+            continue;
+        };
+        let Some(entry) = post.entry()? else {
+            continue;
+        };
+        tx.send(PostInfo(post.id, (*feed).clone(), PostKind::New(entry)))
+            .await?;
+    }
+    for post in PostItem::find_pending_edits().all(&db).await? {
+        let Some(feed) = feeds.get(&post.source) else {
+            continue;
+        };
+        let Some(entry) = post.pending_edit()? else {
+            continue;
+        };
+        tx.send(PostInfo(post.id, (*feed).clone(), PostKind::Edit(entry)))
+            .await?;
+    }
+    for post in PostItem::find_pending_retracts().all(&db).await? {
+        let Some(feed) = feeds.get(&post.source) else {
+            continue;
+        };
+        tx.send(PostInfo(post.id, (*feed).clone(), PostKind::Retract))
+            .await?;
+    }
+    update_queue_depth(&db).await;
+    db.close().await?;
+    Ok(())
+}
+
+/// 保存期間を過ぎた取得履歴を1日ごとに削除します。保存期間は設定の再読み込みに合わせて変わります。
+async fn prune_fetch_log_loop(global: watch::Receiver<Arc<Config>>) {
+    let mut interval = tokio::time::interval(Duration::days(1).to_std().unwrap());
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        // 最初の tick はすぐに返るので起動時にも削除する
+        interval.tick().await;
+        let retention = global.borrow().fetch_log_retention();
+        let db = match setup_connection().await {
+            Ok(db) => db,
+            Err(e) => {
+                warn!(error = &e as &dyn Error, "failed to connect database");
+                continue;
+            }
+        };
+        match FetchLog::prune(&db, retention).await {
+            Ok(0) => {}
+            Ok(deleted) => info!(deleted, "fetch log pruned"),
+            Err(e) => warn!(error = error_value(&e), "failed to prune fetch log"),
+        }
+        if let Err(e) = db.close().await {
+            warn!(error = &e as &dyn Error, "failed to close database");
+        }
+    }
+}
+
+/// 読み込んだ設定に合わせてフィードを追加、再起動、停止します。
+async fn apply_config(config: &Config, tx: &Sender<PostInfo>, feeds: &Feeds) -> anyhow::Result<()> {
+    let db = setup_connection().await?;
+    let mut added = vec![];
+    let mut changed = vec![];
+    let ids = config
+        .feeds
+        .iter()
+        .map(|f| f.id.clone())
+        .collect::<HashSet<_>>();
+
+    // DBを読む間は管理APIを止めないようにロックせずに変更を集める
+    let running = feeds
+        .lock()
+        .await
+        .iter()
+        .map(|(id, r)| {
+            (
+                id.clone(),
+                (r.config.clone(), r.schedule, r.control.clone()),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    // 設定から削除されたフィードは停止
+    let removed = running
+        .keys()
+        .filter(|id| !ids.contains(*id))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut starts = vec![];
+    for feed in &config.feeds {
+        let schedule = config.schedule(feed);
+        // 再起動しても一時停止の状態は引き継ぐ
+        let control = match running.get(&feed.id) {
+            // 変更がなければそのまま
+            Some((c, s, _)) if *c == *feed && *s == schedule => continue,
+            // 変更があれば再起動
+            Some((_, _, control)) => {
+                changed.push(feed.id.clone());
+                control.clone()
+            }
+            None => {
+                added.push(feed.id.clone());
+                Arc::new(FeedControl::default())
+            }
+        };
+        let info = FeedInfo::find_by_id(&feed.id)
+            .one(&db)
+            .await?
+            .unwrap_or(feed_info::Model::new(feed.id.clone()));
+        starts.push((feed.clone(), schedule, control, info.next_fetch));
+    }
+    db.close().await?;
+
+    let mut feeds = feeds.lock().await;
+    for id in &removed {
+        if let Some(running) = feeds.remove(id) {
+            running.handle.abort();
+        }
+    }
+    for (feed, schedule, control, next_fetch) in starts {
+        if let Some(running) = feeds.remove(&feed.id) {
+            running.handle.abort();
+        }
+        let tx = tx.clone();
+        let task = feed.clone();
+        let task_control = control.clone();
+        let span = info_span!("feed", feed = %feed.id);
+        let handle = tokio::spawn(
+            async move {
+                _ = feed_loop(&task, &schedule, next_fetch, tx, &task_control).await;
+            }
+            .instrument(span),
+        );
+        feeds.insert(
+            feed.id.clone(),
+            RunningFeed {
+                config: feed,
+                schedule,
+                control,
+                handle,
+            },
+        );
+    }
+    drop(feeds);
+    info!(?added, ?removed, ?changed, "config reloaded");
+    Ok(())
+}
+
+async fn config_reload_loop(
+    tx: Sender<PostInfo>,
+    feeds: Feeds,
+    global: watch::Sender<Arc<Config>>,
+) {
+    let mut interval = *CONFIG_INTERVAL;
     loop {
         match load_config() {
             Ok(config) => {
-                println!("config reloaded");
-                for feed in config.feeds {
-                    if !feeds.insert(feed.id.clone()) {
-                        continue;
-                    }
-                    let db = setup_connection().await?;
-                    let info = FeedInfo::find_by_id(&feed.id)
-                        .one(&db)
-                        .await?
-                        .unwrap_or(feed_info::Model::new(feed.id.clone()));
-                    let tx = tx.clone();
-                    tokio::spawn(async move {
-                        _ = feed_loop(&feed, info.next_fetch, tx).await;
-                    });
+                interval = config.config_interval();
+                // 投稿ループと管理APIは次の処理から新しい設定を使う
+                let config = Arc::new(config);
+                global.send_replace(config.clone());
+                // DBの一時的な障害で止まらないよう、次の間隔で再試行する
+                if let Err(e) = apply_config(&config, &tx, &feeds).await {
+                    error!(
+                        error = error_value(&e),
+                        "failed to apply config, retrying next time"
+                    );
                 }
             }
+            // 誤りのある設定は反映せず、前回の設定のまま動かし続ける
             Err(e) => {
-                println!("failed to load config: {:?}", e);
+                error!(
+                    error = &*e,
+                    "failed to load config, keeping previous config"
+                );
             }
         }
-        sleep(&CONFIG_INTERVAL, "config wait").await;
+        sleep(&interval, "config wait").await;
     }
 }
 
 fn main() {
-    let _guard = sentry::init(sentry::ClientOptions {
+    let cli = Cli::parse();
+    let guard = sentry::init(sentry::ClientOptions {
         release: sentry::release_name!(),
         #[cfg(debug_assertions)]
         debug: true,
@@ -306,16 +1312,31 @@ fn main() {
         ..Default::default()
     });
 
-    if let Some(release) = sentry::release_name!() {
-        println!("{}", release);
-    }
+    setup_tracing();
     // sentryを動かすために必要
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(run())
         .unwrap();
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            if let Some(release) = sentry::release_name!() {
+                info!(%release, "start");
+            }
+            if let Err(e) = runtime.block_on(run()) {
+                error!(error = &*e, "failed to start");
+                drop(guard);
+                std::process::exit(1);
+            }
+        }
+        command => {
+            if let Err(e) = runtime.block_on(cli::execute(command)) {
+                error!(error = error_value(&e), "failed");
+                drop(guard);
+                std::process::exit(1);
+            }
+        }
+    }
 }
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
@@ -324,10 +1345,99 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
     setup_tables().await?;
 
     let (tx, rx) = channel(*MAX_QUEUE);
+    let feeds = Feeds::default();
+    // 再読み込みした設定を投稿ループと管理APIに伝える
+    let (global_tx, global_rx) = watch::channel(Arc::new(config.clone()));
+    let db = setup_connection().await?;
 
-    _ = tokio::join!(
-        post_loop(rx, &config.base_url, &config.tag, &is_dry_run),
-        config_reload_loop(tx)
-    );
-    Ok(())
+    if let Some(addr) = METRICS_ADDR.as_ref() {
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&addr).await {
+                error!(error = error_value(&e), "failed to serve metrics");
+            }
+        });
+    }
+
+    if let Some(addr) = ADMIN_ADDR.as_ref() {
+        let state = AdminState {
+            global: global_rx.clone(),
+            feeds: feeds.clone(),
+            token: ADMIN_TOKEN.clone(),
+        };
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(&addr, state).await {
+                error!(error = error_value(&e), "failed to serve admin api");
+            }
+        });
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!(timeout = %SHUTDOWN_TIMEOUT.to_iso8601(), "shutting down");
+        _ = shutdown_tx.send(true);
+    });
+
+    let mut reload_shutdown = shutdown_rx.clone();
+    let main = async {
+        tokio::try_join!(
+            async {
+                post_loop(
+                    &db,
+                    rx,
+                    global_rx.clone(),
+                    feeds.clone(),
+                    &is_dry_run,
+                    shutdown_rx.clone(),
+                )
+                .await;
+                Ok(())
+            },
+            async {
+                tokio::select! {
+                    _ = async {
+                        if let Err(e) = restore_queue(&config, &tx).await {
+                            error!(error = error_value(&e), "failed to restore queue");
+                        }
+                        tokio::select! {
+                            _ = config_reload_loop(tx, feeds.clone(), global_tx) => {}
+                            _ = prune_fetch_log_loop(global_rx.clone()) => {}
+                        }
+                    } => Err(anyhow::anyhow!("config reload stopped unexpectedly")),
+                    _ = reload_shutdown.wait_for(|s| *s) => {
+                        // フィードの取得を止める (送信側が全て閉じると post_loop が残りを数えて終わる)
+                        for (_, running) in feeds.lock().await.drain() {
+                            running.handle.abort();
+                        }
+                        Ok(())
+                    }
+                }
+            }
+        )
+    };
+    let mut deadline_shutdown = shutdown_rx.clone();
+    let result = tokio::select! {
+        r = main => r.map(|_| ()),
+        _ = async {
+            _ = deadline_shutdown.wait_for(|s| *s).await;
+            tokio::time::sleep(SHUTDOWN_TIMEOUT.to_std().unwrap()).await;
+        } => {
+            warn!("shutdown timed out");
+            Ok(())
+        }
+    };
+    // 時間切れで投稿を打ち切った場合も接続を閉じる
+    db.close().await?;
+    Ok(result?)
+}
+
+/// SIGTERM または SIGINT を待ちます。
+async fn wait_for_signal() {
+    let mut term = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
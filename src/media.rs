@@ -0,0 +1,102 @@
+use chrono::Utc;
+use megalodon::{entities::UploadMedia, megalodon::UploadMediaInputOptions, Megalodon};
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use tracing::debug;
+
+use crate::constants::MEDIA_PROCESS_TIMEOUT;
+use crate::ext_trait::StatusImage;
+use crate::schema::MediaConfig;
+
+/// 画像をダウンロードしてアップロードし、添付するメディアのIDを返します。
+/// サーバーで非同期に処理される場合は処理が終わるまで待ちます。
+pub async fn upload_image(
+    client: &(dyn Megalodon + Send + Sync),
+    server: &str,
+    token: &str,
+    config: &MediaConfig,
+    image: &StatusImage,
+) -> anyhow::Result<String> {
+    let body = download_image(config, image).await?;
+    let options = UploadMediaInputOptions {
+        description: image.alt.clone(),
+        ..Default::default()
+    };
+    let res = client
+        .upload_media_reader(Box::new(std::io::Cursor::new(body)), Some(&options))
+        .await?;
+    let id = match res.json() {
+        UploadMedia::Attachment(a) => a.id,
+        UploadMedia::AsyncAttachment(a) => {
+            wait_processed(server, token, &a.id).await?;
+            a.id
+        }
+    };
+    Ok(id)
+}
+
+/// メディアの処理が終わるまで待ちます。時間内に終わらない場合はエラーを返します。
+/// megalodon の get_media は処理中 (url が null) だと panic するため、APIを直接呼び出す
+async fn wait_processed(server: &str, token: &str, id: &str) -> anyhow::Result<()> {
+    let url = format!("{}/api/v1/media/{}", server.trim_end_matches('/'), id);
+    let deadline = Utc::now() + *MEDIA_PROCESS_TIMEOUT;
+    let client = reqwest::Client::new();
+    loop {
+        let res = client
+            .get(&url)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?;
+        // 処理中は 206 Partial Content が返る
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Ok(());
+        }
+        if Utc::now() >= deadline {
+            return Err(anyhow::anyhow!(format!(
+                "media processing timed out: {}",
+                id
+            )));
+        }
+        debug!(id, "waiting for media processing");
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+/// Content-Type とサイズを確認しながら画像をダウンロードします。
+async fn download_image(config: &MediaConfig, image: &StatusImage) -> anyhow::Result<Vec<u8>> {
+    let mut res = reqwest::get(&image.url).await?.error_for_status()?;
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<mime::Mime>().ok());
+    let content_type = match content_type {
+        Some(c) if config.accepts(c.essence_str()) => c,
+        c => {
+            return Err(anyhow::anyhow!(format!(
+                "unsupported content type: {:?}, {}",
+                c, image.url
+            )));
+        }
+    };
+    let max_size = config.max_size();
+    if res.content_length().is_some_and(|l| l > max_size) {
+        return Err(anyhow::anyhow!(format!(
+            "image too large: {:?}, {}",
+            res.content_length(),
+            image.url
+        )));
+    }
+    // Content-Length がない場合もあるので読みながら確認する
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > max_size {
+            return Err(anyhow::anyhow!(format!(
+                "image too large: {} ({})",
+                image.url, content_type
+            )));
+        }
+    }
+    Ok(body)
+}
@@ -0,0 +1,154 @@
+use axum::{http::header::CONTENT_TYPE, routing::get, Router};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
+};
+use sea_orm::{DatabaseConnection, PaginatorTrait};
+use tracing::{debug, info, warn};
+
+use crate::post_item::Entity as PostItem;
+
+pub static FETCH_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mastaker_fetch_total",
+        "フィードの取得回数 (HTTPステータス別)",
+        &["feed", "status"]
+    )
+    .unwrap()
+});
+pub static PARSE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mastaker_parse_failures_total",
+        "フィードの解析に失敗した回数",
+        &["feed"]
+    )
+    .unwrap()
+});
+pub static FETCH_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "mastaker_fetch_duration_seconds",
+        "フィードの取得にかかった時間",
+        &["feed"]
+    )
+    .unwrap()
+});
+pub static ENTRIES_DISCOVERED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mastaker_entries_discovered_total",
+        "新着として投稿キューに追加した記事の数",
+        &["feed"]
+    )
+    .unwrap()
+});
+pub static POSTS_SUCCEEDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mastaker_posts_succeeded_total",
+        "投稿に成功した回数",
+        &["feed", "destination"]
+    )
+    .unwrap()
+});
+pub static POSTS_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mastaker_posts_failed_total",
+        "投稿に失敗した回数 (エラーの種類別)",
+        &["feed", "destination", "class"]
+    )
+    .unwrap()
+});
+pub static POST_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mastaker_post_retries_total",
+        "429 により再試行した回数",
+        &["feed", "destination"]
+    )
+    .unwrap()
+});
+pub static QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("mastaker_queue_depth", "投稿されていないキューの数").unwrap()
+});
+pub static POST_LAG: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "mastaker_post_lag_seconds",
+        "記事の公開から投稿までの時間",
+        &["feed"],
+        vec![60.0, 300.0, 900.0, 1800.0, 3600.0, 10800.0, 43200.0, 86400.0]
+    )
+    .unwrap()
+});
+pub static NEXT_FETCH_DELAY: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "mastaker_next_fetch_delay_seconds",
+        "次にフィードを取得するまでの待機時間",
+        &["feed"]
+    )
+    .unwrap()
+});
+pub static FEED_FAILURES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "mastaker_feed_consecutive_failures",
+        "連続して取得に失敗している回数",
+        &["feed"]
+    )
+    .unwrap()
+});
+
+/// 投稿の失敗をメトリクスのラベルに使う種類に分類します。
+pub fn error_class(e: &anyhow::Error) -> &'static str {
+    if let Some(e) = e.downcast_ref::<megalodon::error::Error>() {
+        return match e {
+            megalodon::error::Error::OwnError(e) => match e.status {
+                Some(429) => "rate_limited",
+                Some(s) if (400..500).contains(&s) => "client_error",
+                Some(s) if s >= 500 => "server_error",
+                _ => "api_error",
+            },
+            megalodon::error::Error::RequestError(_) => "network",
+            _ => "api_error",
+        };
+    }
+    if e.downcast_ref::<reqwest::Error>().is_some() {
+        return "network";
+    }
+    if e.downcast_ref::<minijinja::Error>().is_some() {
+        return "template";
+    }
+    "other"
+}
+
+/// 投稿されていないキューの数を数え直します。キューの追加、復元、投稿の成否、取り消しのたびに呼び出します。
+pub async fn update_queue_depth(db: &DatabaseConnection) {
+    match PostItem::find_queued().count(db).await {
+        Ok(queue_count) => {
+            debug!(queue_count, "queue count");
+            QUEUE_DEPTH.set(queue_count as i64);
+        }
+        Err(e) => warn!(
+            error = &e as &dyn std::error::Error,
+            "failed to count queue"
+        ),
+    }
+}
+
+/// Prometheus 向けのメトリクスを公開します。
+pub async fn serve(addr: &str) -> anyhow::Result<()> {
+    let app = Router::new().route("/metrics", get(metrics));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(addr, "metrics listening");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn metrics() -> ([(axum::http::HeaderName, String); 1], Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        warn!(
+            error = &e as &dyn std::error::Error,
+            "failed to encode metrics"
+        );
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], buffer)
+}
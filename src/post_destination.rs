@@ -0,0 +1,84 @@
+use sea_orm::{entity::prelude::*, Set};
+
+/// 投稿先ごとの投稿結果
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "post_destination")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    #[sea_orm(indexed)]
+    pub item_id: i32,
+    pub destination: String,
+    pub post_id: String,
+    /// 添付したメディアのID (JSON)。編集で画像が外れないように送り直す
+    #[sea_orm(column_type = "Text", nullable)]
+    pub media_ids: Option<String>,
+    /// 取り下げの削除や返信が済んだか (再試行で重複して返信しないようにする)
+    #[sea_orm(default_value = false)]
+    pub retracted: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    /// 添付したメディアのIDを取得します。
+    pub fn media_ids(&self) -> anyhow::Result<Vec<String>> {
+        let Some(media_ids) = &self.media_ids else {
+            return Ok(vec![]);
+        };
+        Ok(serde_json::from_str(media_ids)?)
+    }
+}
+
+impl Entity {
+    pub async fn insert(
+        db: &DatabaseConnection,
+        item_id: i32,
+        destination: &str,
+        post_id: &str,
+        media_ids: &[String],
+    ) -> Result<Model, anyhow::Error> {
+        let media_ids = if media_ids.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(media_ids)?)
+        };
+        let post = ActiveModel {
+            item_id: Set(item_id),
+            destination: Set(destination.to_owned()),
+            post_id: Set(post_id.to_owned()),
+            media_ids: Set(media_ids),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+        Ok(post)
+    }
+
+    /// 投稿済みの投稿先を取得します。
+    pub async fn find_by_item(
+        db: &DatabaseConnection,
+        item_id: i32,
+    ) -> Result<Vec<Model>, anyhow::Error> {
+        let posts = Self::find()
+            .filter(Column::ItemId.eq(item_id))
+            .all(db)
+            .await?;
+        Ok(posts)
+    }
+
+    /// 取り下げが済んだことを記録します。
+    pub async fn mark_retracted(db: &DatabaseConnection, id: i32) -> Result<(), anyhow::Error> {
+        ActiveModel {
+            id: Set(id),
+            retracted: Set(true),
+            ..Default::default()
+        }
+        .update(db)
+        .await?;
+        Ok(())
+    }
+}
@@ -1,8 +1,12 @@
+use std::collections::HashSet;
+
 use chrono::Utc;
 use feed_rs::model::Entry;
-use sea_orm::{entity::prelude::*, Set};
+use sea_orm::{entity::prelude::*, Condition, QueryOrder, QuerySelect, Set};
 
+use crate::constants::{MAX_POST_ATTEMPTS, RECENT_POST_LIMIT, RETRY_INTERVAL};
 use crate::ext_trait::ItemExt;
+use crate::utility::normalize_link;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "post_item")]
@@ -11,12 +15,37 @@ pub struct Model {
     pub id: i32,
     #[sea_orm(indexed)]
     pub source: String,
+    /// フィードの記事ID (GUID)
+    #[sea_orm(indexed)]
+    pub guid: Option<String>,
     pub title: String,
+    /// 正規化済みのリンク
     pub link: String,
     #[sea_orm(indexed)]
     pub post_id: Option<String>,
     #[sea_orm(indexed)]
     pub pub_date: DateTimeUtc,
+    /// 投稿キューに追加されたエントリー (JSON)
+    #[sea_orm(column_type = "Text", nullable)]
+    pub entry: Option<String>,
+    #[sea_orm(default_value = 0)]
+    pub attempts: i32,
+    /// 投稿キューに追加した日時 (送信前に中断されたキューの再試行に使う)
+    pub queued_at: Option<DateTimeUtc>,
+    /// 最後にフィードで確認した日時 (取り下げの判定に使う)
+    pub last_seen: Option<DateTimeUtc>,
+    /// フィードで連続して見つからなかった取得回数 (取り下げの判定に使う)
+    #[sea_orm(default_value = 0)]
+    pub missing: i32,
+    /// 取り下げの削除や返信を待っているか
+    #[sea_orm(default_value = false)]
+    pub retract_pending: bool,
+    /// 取り下げ済みか
+    #[sea_orm(default_value = false)]
+    pub retracted: bool,
+    /// 編集待ちの更新された記事 (JSON)。全ての投稿先で編集できたら title と link に反映する
+    #[sea_orm(column_type = "Text", nullable)]
+    pub pending_edit: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -24,21 +53,298 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
+impl Model {
+    /// 投稿キューに保存されたエントリーを復元します。
+    pub fn entry(&self) -> anyhow::Result<Option<Entry>> {
+        let Some(entry) = &self.entry else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(entry)?))
+    }
+
+    /// 編集待ちの更新された記事を復元します。
+    pub fn pending_edit(&self) -> anyhow::Result<Option<Entry>> {
+        let Some(entry) = &self.pending_edit else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(entry)?))
+    }
+}
+
 impl Entity {
+    /// 投稿せずに登録のみ行います。
     pub async fn insert(
         db: &DatabaseConnection,
         source: &String,
         entry: &Entry,
+    ) -> Result<Model, anyhow::Error> {
+        let post = Self::new_model(source, entry).insert(db).await?;
+        Ok(post)
+    }
+
+    /// 投稿キューに追加します。
+    /// 再起動しても投稿できるようにエントリーも保存します。
+    pub async fn enqueue(
+        db: &DatabaseConnection,
+        source: &String,
+        entry: &Entry,
     ) -> Result<Model, anyhow::Error> {
         let post = ActiveModel {
+            entry: Set(Some(serde_json::to_string(entry)?)),
+            queued_at: Set(Some(Utc::now())),
+            ..Self::new_model(source, entry)
+        }
+        .insert(db)
+        .await?;
+        Ok(post)
+    }
+
+    /// 更新された記事を編集待ちとして保存します。編集の試行回数は数え直します。
+    pub async fn queue_edit(
+        db: &DatabaseConnection,
+        id: i32,
+        entry: &Entry,
+    ) -> Result<(), anyhow::Error> {
+        ActiveModel {
+            id: Set(id),
+            pending_edit: Set(Some(serde_json::to_string(entry)?)),
+            attempts: Set(0),
+            ..Default::default()
+        }
+        .update(db)
+        .await?;
+        Ok(())
+    }
+
+    /// 全ての投稿先で編集できた記事のタイトルとリンクを保存します。
+    /// 編集中にさらに更新された場合は新しい編集待ちを残します。
+    pub async fn finish_edit(
+        db: &DatabaseConnection,
+        id: i32,
+        entry: &Entry,
+    ) -> Result<(), anyhow::Error> {
+        let json = serde_json::to_string(entry)?;
+        Self::update_many()
+            .col_expr(
+                Column::Title,
+                Expr::value(entry.title.as_ref().unwrap().content.to_owned()),
+            )
+            .col_expr(Column::Link, Expr::value(entry.normalized_link()))
+            .col_expr(Column::Entry, Expr::value(json.clone()))
+            .col_expr(Column::PendingEdit, Expr::value(Option::<String>::None))
+            .col_expr(Column::Attempts, Expr::value(0))
+            .filter(Column::Id.eq(id))
+            .filter(Column::PendingEdit.eq(json))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    /// 投稿や編集の試行回数を1つ増やします。
+    pub async fn add_attempt(db: &DatabaseConnection, id: i32) -> Result<(), anyhow::Error> {
+        Self::update_many()
+            .col_expr(Column::Attempts, Expr::col(Column::Attempts).add(1))
+            .filter(Column::Id.eq(id))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    /// 投稿されていないキューを取り消します。記事は登録済みのまま残します。
+    pub async fn drop_queued(db: &DatabaseConnection, id: i32) -> Result<bool, anyhow::Error> {
+        let res = Self::update_many()
+            .col_expr(Column::Entry, Expr::value(Option::<String>::None))
+            .filter(Column::Id.eq(id))
+            .filter(Column::PostId.is_null())
+            .filter(Column::Entry.is_not_null())
+            .exec(db)
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
+    /// フィードに含まれている記事の確認日時を更新します。
+    pub async fn update_last_seen(
+        db: &DatabaseConnection,
+        source: &String,
+        guids: HashSet<String>,
+    ) -> Result<(), anyhow::Error> {
+        Self::update_many()
+            .col_expr(Column::LastSeen, Expr::value(Utc::now()))
+            .col_expr(Column::Missing, Expr::value(0))
+            .filter(Column::Source.eq(source))
+            .filter(Column::Guid.is_in(guids))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    /// フィードで見つからなかった回数を1つ増やします。
+    pub async fn add_missing(db: &DatabaseConnection, ids: Vec<i32>) -> Result<(), anyhow::Error> {
+        Self::update_many()
+            .col_expr(Column::Missing, Expr::col(Column::Missing).add(1))
+            .filter(Column::Id.is_in(ids))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    /// 取り下げの削除や返信を待っている状態にします。試行回数は数え直します。
+    pub async fn queue_retract(db: &DatabaseConnection, id: i32) -> Result<(), anyhow::Error> {
+        ActiveModel {
+            id: Set(id),
+            retract_pending: Set(true),
+            attempts: Set(0),
+            ..Default::default()
+        }
+        .update(db)
+        .await?;
+        Ok(())
+    }
+
+    /// 全ての投稿先で取り下げられたことを記録します。
+    pub async fn mark_retracted(db: &DatabaseConnection, id: i32) -> Result<(), anyhow::Error> {
+        ActiveModel {
+            id: Set(id),
+            retract_pending: Set(false),
+            retracted: Set(true),
+            ..Default::default()
+        }
+        .update(db)
+        .await?;
+        Ok(())
+    }
+
+    /// GUIDを保存していない旧バージョンの登録分にGUIDと正規化したリンクを補い、補った件数を返します。
+    /// 旧バージョンは最新の記事しか登録していないため、フィードに残っている記事のうち
+    /// 最後の登録以前のもの (公開日時のないものを含む) は投稿せずに登録します。
+    /// フィードから消えた登録分はリンクをGUIDとし、次回以降は補わないようにします。
+    pub async fn backfill_guids(
+        db: &DatabaseConnection,
+        source: &String,
+        entries: &[&Entry],
+    ) -> Result<usize, anyhow::Error> {
+        let legacy = Self::find()
+            .filter(Column::Source.eq(source))
+            .filter(Column::Guid.is_null())
+            .all(db)
+            .await?;
+        let Some(until) = legacy.iter().map(|p| p.pub_date).max() else {
+            return Ok(0);
+        };
+        let mut remaining = legacy.iter().collect::<Vec<_>>();
+        for entry in entries {
+            let link = entry.normalized_link();
+            let title = entry.title.as_ref().map(|t| t.content.as_str());
+            let found = remaining.iter().position(|p| {
+                normalize_link(&p.link) == link
+                    || (Some(p.title.as_str()) == title
+                        && entry.pub_date_utc() == Some(&p.pub_date))
+            });
+            match found {
+                Some(i) => {
+                    let p = remaining.remove(i);
+                    Self::set_guid(db, p.id, &entry.id, &link).await?;
+                }
+                None if entry.pub_date_utc().is_none_or(|d| *d <= until) => {
+                    Self::insert(db, source, entry).await?;
+                }
+                None => {}
+            }
+        }
+        for p in remaining {
+            let link = normalize_link(&p.link);
+            Self::set_guid(db, p.id, &link, &link).await?;
+        }
+        Ok(legacy.len())
+    }
+
+    async fn set_guid(
+        db: &DatabaseConnection,
+        id: i32,
+        guid: &str,
+        link: &str,
+    ) -> Result<(), anyhow::Error> {
+        ActiveModel {
+            id: Set(id),
+            guid: Set(Some(guid.to_owned())),
+            link: Set(link.to_owned()),
+            ..Default::default()
+        }
+        .update(db)
+        .await?;
+        Ok(())
+    }
+
+    /// 新着判定に使う直近の登録済み記事を取得します。
+    pub async fn find_recent(
+        db: &DatabaseConnection,
+        source: &String,
+    ) -> Result<Vec<Model>, anyhow::Error> {
+        let posts = Self::find()
+            .filter(Column::Source.eq(source))
+            .order_by_desc(Column::Id)
+            .limit(*RECENT_POST_LIMIT)
+            .all(db)
+            .await?;
+        Ok(posts)
+    }
+
+    /// 投稿されていないキューを古い順に取得します。
+    pub fn find_queued() -> Select<Self> {
+        Self::find()
+            .filter(Column::PostId.is_null())
+            .filter(Column::Entry.is_not_null())
+            .filter(Column::Attempts.lt(*MAX_POST_ATTEMPTS))
+            .order_by_asc(Column::Id)
+    }
+
+    /// 投稿に失敗して再試行を待っているキューを古い順に取得します。
+    /// 設定の再読み込みや終了でフィードが止まり、投稿ループに送られなかったキューも含めます。
+    pub fn find_retry() -> Select<Self> {
+        Self::find_queued().filter(
+            Condition::any()
+                .add(Column::Attempts.gt(0))
+                .add(Column::QueuedAt.lt(Utc::now() - *RETRY_INTERVAL)),
+        )
+    }
+
+    /// 編集待ちの投稿済み記事を古い順に取得します。
+    pub fn find_pending_edits() -> Select<Self> {
+        Self::find()
+            .filter(Column::PostId.is_not_null())
+            .filter(Column::PendingEdit.is_not_null())
+            .filter(Column::Attempts.lt(*MAX_POST_ATTEMPTS))
+            .order_by_asc(Column::Id)
+    }
+
+    /// 編集に失敗して再試行を待っている記事を古い順に取得します。
+    pub fn find_edit_retry() -> Select<Self> {
+        Self::find_pending_edits().filter(Column::Attempts.gt(0))
+    }
+
+    /// 取り下げを待っている投稿済み記事を古い順に取得します。
+    pub fn find_pending_retracts() -> Select<Self> {
+        Self::find()
+            .filter(Column::PostId.is_not_null())
+            .filter(Column::RetractPending.eq(true))
+            .filter(Column::Retracted.eq(false))
+            .filter(Column::Attempts.lt(*MAX_POST_ATTEMPTS))
+            .order_by_asc(Column::Id)
+    }
+
+    /// 取り下げに失敗して再試行を待っている記事を古い順に取得します。
+    pub fn find_retract_retry() -> Select<Self> {
+        Self::find_pending_retracts().filter(Column::Attempts.gt(0))
+    }
+
+    fn new_model(source: &String, entry: &Entry) -> ActiveModel {
+        ActiveModel {
             source: Set(source.to_owned()),
+            guid: Set(Some(entry.id.clone())),
             title: Set(entry.title.as_ref().unwrap().content.to_owned()),
-            link: Set(entry.links.get(0).unwrap().href.clone()),
+            link: Set(entry.normalized_link()),
             pub_date: Set(*entry.pub_date_utc_or(&Utc::now())),
             ..Default::default()
         }
-        .insert(db)
-        .await?;
-        Ok(post)
     }
 }
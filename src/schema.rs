@@ -1,21 +1,484 @@
+use crate::constants::{
+    CONFIG_INTERVAL, DEAD_AFTER, DEFAULT_DESTINATION, DEFAULT_MEDIA_MAX_SIZE, DEFAULT_MEDIA_TYPES,
+    FETCH_LOG_DAYS, MAX_BACKOFF, MAX_WAIT, MIN_WAIT, POST_INTERVAL, QUEUE_INTERVAL,
+};
+use crate::template::validate_template;
+use chrono::Duration;
+use megalodon::entities::StatusVisibility;
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashSet, env, fs};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub base_url: String,
+    /// 投稿先のSNS (省略時は自動判定)
+    pub sns: Option<Sns>,
     pub tag: Option<TagConfig>,
+    /// 投稿の書式 (minijinja テンプレート)
+    pub template: Option<String>,
+    pub media: Option<MediaConfig>,
+    #[serde(flatten)]
+    pub status: StatusConfig,
+    /// フィードを取得する最短の間隔 (分, 省略時は環境変数 MIN_WAIT)
+    pub min_wait: Option<u32>,
+    /// フィードを取得する最長の間隔 (分, 省略時は環境変数 MAX_WAIT)
+    pub max_wait: Option<u32>,
+    /// 新着を投稿キューに追加する間隔 (秒, 省略時は環境変数 QUEUE_INTERVAL)
+    pub queue_interval: Option<u32>,
+    /// 投稿する間隔 (秒, 省略時は環境変数 POST_INTERVAL)
+    pub post_interval: Option<u32>,
+    /// 設定ファイルを再読み込みする間隔 (秒, 省略時は環境変数 CONFIG_INTERVAL)
+    pub config_interval: Option<u32>,
+    /// 取得に失敗し続けた場合の待機時間の上限 (分, 省略時は環境変数 MAX_BACKOFF)
+    pub max_backoff: Option<u32>,
+    /// 取得に失敗し続けたフィードを停止扱いにするまでの日数 (省略時は環境変数 DEAD_AFTER)
+    pub dead_after: Option<u32>,
+    /// 取得履歴を残す日数 (省略時は環境変数 FETCH_LOG_DAYS)
+    pub fetch_log_days: Option<u32>,
     pub feeds: Vec<FeedConfig>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct FeedConfig {
     pub id: String,
     pub url: String,
-    pub token: String,
+    /// 投稿先のサーバー (省略時は全体の base_url)
+    pub base_url: Option<String>,
+    pub sns: Option<Sns>,
+    pub token: Option<Secret>,
     pub tag: Option<TagConfig>,
+    pub template: Option<String>,
+    /// 指定した場合は記事の画像を添付する
+    pub media: Option<MediaConfig>,
+    /// 公開範囲やCWなど (省略時は全体の設定)
+    #[serde(flatten)]
+    pub status: StatusConfig,
+    /// 投稿済みの記事が更新された場合の動作 (省略時は off)
+    pub on_update: Option<UpdateMode>,
+    /// 投稿済みの記事が取り下げられた場合の動作 (省略時は off)
+    pub on_retract: Option<RetractMode>,
+    /// on_retract が reply の場合に返信する文章
+    pub retract_note: Option<String>,
+    /// 複数のアカウントに投稿する場合の投稿先
+    pub destinations: Option<Vec<DestinationConfig>>,
+    /// フィードを取得する最短の間隔 (分, 省略時は全体の設定)
+    pub min_wait: Option<u32>,
+    /// フィードを取得する最長の間隔 (分, 省略時は全体の設定)
+    pub max_wait: Option<u32>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// 設定の誤り
+#[derive(Clone, Debug)]
+pub struct ConfigIssue {
+    /// YAML上の位置 (例: `feeds[0].tag.ignore[1]`)
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn seconds(value: Option<u32>) -> Option<Duration> {
+    value.map(|s| Duration::seconds(s.into()))
+}
+
+/// 設定の誤りを集めます。
+#[derive(Default)]
+struct Issues(Vec<ConfigIssue>);
+
+impl Issues {
+    fn push(&mut self, path: impl Into<String>, message: impl ToString) {
+        self.0.push(ConfigIssue {
+            path: path.into(),
+            message: message.to_string(),
+        });
+    }
+
+    fn url(&mut self, path: String, url: &str) {
+        match reqwest::Url::parse(url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(url) => self.push(path, format!("unsupported scheme: {}", url.scheme())),
+            Err(e) => self.push(path, e),
+        }
+    }
+
+    fn token(&mut self, path: String, token: &str) {
+        if token.trim().is_empty() {
+            self.push(path, "token is empty");
+        }
+    }
+
+    fn wait(
+        &mut self,
+        prefix: String,
+        (min_wait, max_wait): (Duration, Duration),
+        max_backoff: Duration,
+    ) {
+        // 0分にすると取得に失敗し続けた場合に待たずに再試行し続ける
+        if min_wait.is_zero() {
+            self.push(
+                format!("{}min_wait", prefix),
+                "min_wait must be greater than 0",
+            );
+        } else if max_backoff < min_wait {
+            // 全体の設定では max_backoff を、フィードの設定では上書きした min_wait を指摘する
+            let path = if prefix.is_empty() {
+                "max_backoff".to_string()
+            } else {
+                format!("{}min_wait", prefix)
+            };
+            self.push(
+                path,
+                format!(
+                    "max_backoff ({}m) must not be less than min_wait ({}m)",
+                    max_backoff.num_minutes(),
+                    min_wait.num_minutes()
+                ),
+            );
+        }
+        if max_wait.is_zero() {
+            self.push(
+                format!("{}max_wait", prefix),
+                "max_wait must be greater than 0",
+            );
+        } else if min_wait > max_wait {
+            self.push(
+                format!("{}min_wait", prefix),
+                format!(
+                    "min_wait ({}m) must not exceed max_wait ({}m)",
+                    min_wait.num_minutes(),
+                    max_wait.num_minutes()
+                ),
+            );
+        }
+    }
+
+    fn tag(&mut self, path: String, tag: &Option<TagConfig>) {
+        let Some(tag) = tag else { return };
+        for (key, patterns) in [("replace", &tag.replace), ("ignore", &tag.ignore)] {
+            for (i, pattern) in patterns.iter().enumerate() {
+                if let Err(e) = Regex::new(pattern) {
+                    self.push(format!("{}.{}[{}]", path, key, i), e);
+                }
+            }
+        }
+        if let Some(xpath) = &tag.xpath {
+            match sxd_xpath::Factory::new().build(xpath) {
+                Ok(Some(_)) => {}
+                Ok(None) => self.push(format!("{}.xpath", path), "xpath is empty"),
+                Err(e) => self.push(format!("{}.xpath", path), e),
+            }
+        }
+    }
+
+    fn template(&mut self, path: String, template: &Option<String>) {
+        if let Some(Err(e)) = template.as_deref().map(validate_template) {
+            self.push(path, e);
+        }
+    }
+
+    fn status(&mut self, prefix: &str, status: &StatusConfig) {
+        self.template(format!("{}spoiler_text", prefix), &status.spoiler_text);
+        for (i, rule) in status.cw_rules.iter().enumerate() {
+            let path = format!("{}cw_rules[{}]", prefix, i);
+            if let Err(e) = Regex::new(&rule.pattern) {
+                self.push(format!("{}.pattern", path), e);
+            }
+            self.template(
+                format!("{}.spoiler_text", path),
+                &Some(rule.spoiler_text.clone()),
+            );
+        }
+    }
+}
+
+/// フィードの取得に使う間隔
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeedSchedule {
+    pub min_wait: Duration,
+    pub max_wait: Duration,
+    pub queue_interval: Duration,
+    pub max_backoff: Duration,
+    pub dead_after: Duration,
+}
+
+impl Config {
+    /// フィード、全体、環境変数の順に取得の間隔を決めます。
+    pub fn schedule(&self, feed: &FeedConfig) -> FeedSchedule {
+        let (min_wait, max_wait) = self.wait_range(feed.min_wait, feed.max_wait);
+        FeedSchedule {
+            min_wait,
+            max_wait,
+            queue_interval: self.queue_interval(),
+            max_backoff: self.max_backoff(),
+            dead_after: self.dead_after(),
+        }
+    }
+
+    fn max_backoff(&self) -> Duration {
+        self.max_backoff
+            .map(|m| Duration::minutes(m.into()))
+            .unwrap_or(*MAX_BACKOFF)
+    }
+
+    fn dead_after(&self) -> Duration {
+        self.dead_after
+            .map(|d| Duration::days(d.into()))
+            .unwrap_or(*DEAD_AFTER)
+    }
+
+    fn wait_range(&self, min_wait: Option<u32>, max_wait: Option<u32>) -> (Duration, Duration) {
+        let minutes = |v: Option<u32>| v.map(|m| Duration::minutes(m.into()));
+        (
+            minutes(min_wait.or(self.min_wait)).unwrap_or(*MIN_WAIT),
+            minutes(max_wait.or(self.max_wait)).unwrap_or(*MAX_WAIT),
+        )
+    }
+
+    pub fn queue_interval(&self) -> Duration {
+        seconds(self.queue_interval).unwrap_or(*QUEUE_INTERVAL)
+    }
+
+    pub fn post_interval(&self) -> Duration {
+        seconds(self.post_interval).unwrap_or(*POST_INTERVAL)
+    }
+
+    pub fn config_interval(&self) -> Duration {
+        seconds(self.config_interval).unwrap_or(*CONFIG_INTERVAL)
+    }
+
+    pub fn fetch_log_retention(&self) -> Duration {
+        self.fetch_log_days
+            .map(|d| Duration::days(d.into()))
+            .unwrap_or(*FETCH_LOG_DAYS)
+    }
+
+    /// 投稿先、フィード、全体の順に投稿先のサーバーを決めます。
+    pub fn server<'a>(&'a self, feed: &'a FeedConfig, dest: &'a DestinationConfig) -> &'a str {
+        dest.base_url
+            .as_deref()
+            .or(feed.base_url.as_deref())
+            .unwrap_or(&self.base_url)
+    }
+
+    /// トークンの参照 (`${ENV_VAR}`, `file:/path`) を実際の値に置き換えます。
+    /// 解決できなかった参照を返します。
+    pub fn resolve_secrets(&mut self) -> Vec<ConfigIssue> {
+        let mut issues = Issues::default();
+        for (i, feed) in self.feeds.iter_mut().enumerate() {
+            if let Some(Err(e)) = feed.token.as_mut().map(Secret::resolve) {
+                issues.push(format!("feeds[{}].token", i), e);
+            }
+            for (j, dest) in feed.destinations.iter_mut().flatten().enumerate() {
+                if let Err(e) = dest.token.resolve() {
+                    issues.push(format!("feeds[{}].destinations[{}].token", i, j), e);
+                }
+            }
+        }
+        issues.0
+    }
+
+    /// 正規表現、XPath、URL、テンプレートなど実行時まで分からない設定の誤りを全て検出します。
+    /// 誤りがなければ空の一覧を返します。
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Issues::default();
+        issues.url("base_url".to_string(), &self.base_url);
+        issues.tag("tag".to_string(), &self.tag);
+        issues.template("template".to_string(), &self.template);
+        issues.status("", &self.status);
+        issues.wait(
+            String::new(),
+            self.wait_range(None, None),
+            self.max_backoff(),
+        );
+        if self.dead_after().is_zero() {
+            issues.push("dead_after", "dead_after must be greater than 0");
+        }
+        if self.config_interval == Some(0) {
+            issues.push("config_interval", "config_interval must be greater than 0");
+        }
+        let mut ids = HashSet::new();
+        for (i, feed) in self.feeds.iter().enumerate() {
+            let path = format!("feeds[{}]", i);
+            if !ids.insert(&feed.id) {
+                issues.push(
+                    format!("{}.id", path),
+                    format!("duplicate feed id: {}", feed.id),
+                );
+            }
+            issues.url(format!("{}.url", path), &feed.url);
+            if let Some(base_url) = &feed.base_url {
+                issues.url(format!("{}.base_url", path), base_url);
+            }
+            // 全体の設定の誤りをフィードごとに繰り返さないよう、上書きしている場合のみ検証する
+            if feed.min_wait.is_some() || feed.max_wait.is_some() {
+                issues.wait(
+                    format!("{}.", path),
+                    self.wait_range(feed.min_wait, feed.max_wait),
+                    self.max_backoff(),
+                );
+            }
+            match (&feed.token, &feed.destinations) {
+                (_, Some(destinations)) if !destinations.is_empty() => {}
+                (Some(token), _) => issues.token(format!("{}.token", path), token.expose()),
+                (None, _) => issues.push(path.clone(), "token or destinations is required"),
+            }
+            issues.tag(format!("{}.tag", path), &feed.tag);
+            issues.template(format!("{}.template", path), &feed.template);
+            issues.status(&format!("{}.", path), &feed.status);
+            let mut dest_ids = HashSet::new();
+            for (j, dest) in feed.destinations.iter().flatten().enumerate() {
+                let path = format!("{}.destinations[{}]", path, j);
+                if !dest_ids.insert(&dest.id) {
+                    issues.push(
+                        format!("{}.id", path),
+                        format!("duplicate destination id: {}", dest.id),
+                    );
+                }
+                if let Some(base_url) = &dest.base_url {
+                    issues.url(format!("{}.base_url", path), base_url);
+                }
+                issues.token(format!("{}.token", path), dest.token.expose());
+                issues.tag(format!("{}.tag", path), &dest.tag);
+            }
+        }
+        issues.0
+    }
+}
+
+impl FeedConfig {
+    /// 投稿先の一覧を取得します。
+    /// destinations がない場合はフィードの token を投稿先とします。
+    pub fn destinations(&self) -> Vec<DestinationConfig> {
+        match &self.destinations {
+            Some(destinations) if !destinations.is_empty() => destinations.clone(),
+            _ => vec![DestinationConfig {
+                id: DEFAULT_DESTINATION.to_string(),
+                base_url: None,
+                sns: None,
+                token: self.token.clone().unwrap_or_default(),
+                visibility: None,
+                tag: None,
+            }],
+        }
+    }
+}
+
+/// ログに出力されないようにする秘密の値
+/// `${ENV_VAR}` は環境変数から、`file:/path` はファイルから読み込みます。
+#[derive(Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    fn resolve(&mut self) -> Result<(), String> {
+        if let Some(name) = self.0.strip_prefix("${").and_then(|s| s.strip_suffix('}')) {
+            self.0 =
+                env::var(name).map_err(|_| format!("environment variable {} is not set", name))?;
+        } else if let Some(path) = self.0.strip_prefix("file:") {
+            let value = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            // ファイル末尾の改行は含めない
+            self.0 = value.trim_end().to_string();
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DestinationConfig {
+    /// 投稿結果を記録するための識別子
+    pub id: String,
+    pub base_url: Option<String>,
+    pub sns: Option<Sns>,
+    pub token: Secret,
+    pub visibility: Option<StatusVisibility>,
+    pub tag: Option<TagConfig>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatusConfig {
+    pub visibility: Option<StatusVisibility>,
+    /// 投稿の言語 (ISO 639)
+    pub language: Option<String>,
+    /// 添付した画像を閲覧注意にする
+    pub sensitive: Option<bool>,
+    /// CW (minijinja テンプレート)
+    pub spoiler_text: Option<String>,
+    /// タイトルが一致した場合にCWを付ける
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cw_rules: Vec<CwRule>,
+}
+
+impl StatusConfig {
+    /// 上位の設定に下位の設定を重ねます。
+    /// cw_rules は追加し、それ以外は指定されている場合のみ上書きします。
+    pub fn merge(&mut self, other: &StatusConfig) {
+        if other.visibility.is_some() {
+            self.visibility = other.visibility.clone();
+        }
+        if other.language.is_some() {
+            self.language = other.language.clone();
+        }
+        if other.sensitive.is_some() {
+            self.sensitive = other.sensitive;
+        }
+        if other.spoiler_text.is_some() {
+            self.spoiler_text = other.spoiler_text.clone();
+        }
+        self.cw_rules.extend(other.cw_rules.clone());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CwRule {
+    /// タイトルにマッチする正規表現
+    pub pattern: String,
+    /// マッチした場合のCW (minijinja テンプレート)
+    pub spoiler_text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MediaConfig {
+    /// 添付する画像の最大サイズ (バイト)
+    pub max_size: Option<u64>,
+    /// 添付する画像の Content-Type
+    pub content_types: Option<Vec<String>>,
+}
+
+impl MediaConfig {
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(DEFAULT_MEDIA_MAX_SIZE)
+    }
+
+    pub fn accepts(&self, content_type: &str) -> bool {
+        match &self.content_types {
+            Some(types) => types.iter().any(|t| t.eq_ignore_ascii_case(content_type)),
+            None => DEFAULT_MEDIA_TYPES.contains(&content_type),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TagConfig {
     pub always: Vec<String>,
     pub ignore: Vec<String>,
@@ -34,4 +497,186 @@ impl TagConfig {
             keywords: None,
         }
     }
+
+    /// 上位の設定に下位の設定を重ねます。
+    /// always, ignore, replace は追加し、xpath, keywords は上書きします。
+    pub fn merge(&mut self, other: &TagConfig) {
+        self.always.extend(other.always.clone());
+        self.ignore.extend(other.ignore.clone());
+        self.replace.extend(other.replace.clone());
+        self.xpath = other.xpath.clone();
+        self.keywords = other.keywords;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    /// 何もしない
+    Off,
+    /// 投稿済みのステータスを編集する
+    Edit,
+    /// 新着として投稿し直す
+    Repost,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetractMode {
+    /// 何もしない
+    Off,
+    /// 投稿済みのステータスを削除する
+    Delete,
+    /// 投稿済みのステータスに取り下げを返信する
+    Reply,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sns {
+    Mastodon,
+    Pleroma,
+    Friendica,
+    Firefish,
+    Gotosocial,
+}
+
+impl From<&Sns> for megalodon::SNS {
+    fn from(sns: &Sns) -> Self {
+        match sns {
+            Sns::Mastodon => megalodon::SNS::Mastodon,
+            Sns::Pleroma => megalodon::SNS::Pleroma,
+            Sns::Friendica => megalodon::SNS::Friendica,
+            Sns::Firefish => megalodon::SNS::Firefish,
+            Sns::Gotosocial => megalodon::SNS::Gotosocial,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(feeds: &str) -> Config {
+        serde_yaml::from_str(&format!("base_url: https://example.com\nfeeds:\n{}", feeds)).unwrap()
+    }
+
+    fn paths(config: &Config) -> Vec<String> {
+        config.validate().into_iter().map(|i| i.path).collect()
+    }
+
+    #[test]
+    fn valid_config_has_no_issues() {
+        let config = config("- id: a\n  url: https://example.com/feed\n  token: t\n");
+        assert!(paths(&config).is_empty());
+    }
+
+    #[test]
+    fn detects_duplicate_ids() {
+        let config = config(
+            "\
+- id: a
+  url: https://example.com/a
+  token: t
+- id: a
+  url: https://example.com/b
+  destinations:
+  - id: x
+    token: t
+  - id: x
+    token: u
+",
+        );
+        assert_eq!(
+            paths(&config),
+            vec!["feeds[1].id", "feeds[1].destinations[1].id"]
+        );
+    }
+
+    #[test]
+    fn detects_bad_regex_and_xpath() {
+        let config = config(
+            "\
+- id: a
+  url: https://example.com/a
+  token: t
+  tag:
+    always: []
+    replace: ['ok', '(']
+    ignore: ['[']
+    xpath: '//['
+",
+        );
+        assert_eq!(
+            paths(&config),
+            vec![
+                "feeds[0].tag.replace[1]",
+                "feeds[0].tag.ignore[0]",
+                "feeds[0].tag.xpath"
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_invalid_wait_ranges() {
+        let mut config = config(
+            "\
+- id: a
+  url: https://example.com/a
+  token: t
+  min_wait: 30
+  max_wait: 10
+- id: b
+  url: https://example.com/b
+  token: t
+  min_wait: 0
+",
+        );
+        config.max_wait = Some(60);
+        config.max_backoff = Some(120);
+        assert_eq!(
+            paths(&config),
+            vec!["feeds[0].min_wait", "feeds[1].min_wait"]
+        );
+
+        config.feeds.truncate(1);
+        config.feeds[0].min_wait = None;
+        config.feeds[0].max_wait = None;
+        config.min_wait = Some(30);
+        config.max_backoff = Some(10);
+        config.dead_after = Some(0);
+        assert_eq!(paths(&config), vec!["max_backoff", "dead_after"]);
+    }
+
+    #[test]
+    fn resolves_secret_from_env() {
+        env::set_var("MASTAKER_TEST_SECRET", "from-env");
+        let mut secret = Secret::from("${MASTAKER_TEST_SECRET}".to_string());
+        secret.resolve().unwrap();
+        assert_eq!(secret.expose(), "from-env");
+
+        let mut missing = Secret::from("${MASTAKER_TEST_MISSING}".to_string());
+        assert!(missing.resolve().is_err());
+    }
+
+    #[test]
+    fn resolves_secret_from_file() {
+        let path = env::temp_dir().join(format!("mastaker-secret-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+        let mut secret = Secret::from(format!("file:{}", path.display()));
+        let result = secret.resolve();
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        assert_eq!(secret.expose(), "from-file");
+
+        let mut missing = Secret::from("file:/nonexistent/mastaker".to_string());
+        assert!(missing.resolve().is_err());
+    }
+
+    #[test]
+    fn keeps_plain_secret() {
+        let mut secret = Secret::from("plain".to_string());
+        secret.resolve().unwrap();
+        assert_eq!(secret.expose(), "plain");
+    }
 }
@@ -1,18 +1,152 @@
 use crate::constants::*;
 use crate::schema::*;
-use crate::{feed_info, post_item};
-use std::{env, fs::File};
+use crate::yaml_path::YamlPaths;
+use crate::{feed_info, fetch_log, post_destination, post_item};
+use std::{collections::BTreeMap, env, fs, io::IsTerminal};
 
 use feed_info::Entity as FeedInfo;
+use fetch_log::Entity as FetchLog;
+use post_destination::Entity as PostDestination;
 use post_item::Entity as PostItem;
 use sea_orm::*;
 use sea_orm_migration::SchemaManager;
+use sentry::integrations::tracing::{breadcrumb_from_event, event_from_event, EventMapping};
+use tracing::{
+    field::{Field, Visit},
+    info, span, warn, Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    fmt,
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
+/// ログの出力を設定します。Sentryにはログをパンくずリストとして、エラーをイベントとして送ります。
+pub fn setup_tracing() {
+    let filter = EnvFilter::try_new(LOG_LEVEL.as_str()).unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(SpanFieldsLayer)
+        .with(sentry::integrations::tracing::layer().event_mapper(map_event));
+    // コンテナのログに色の制御文字が混ざらないように端末の場合のみ色を付ける
+    let ansi = std::io::stdout().is_terminal();
+    match LOG_FORMAT.as_str() {
+        "json" => registry.with(fmt::layer().json()).init(),
+        "pretty" => registry.with(fmt::layer().pretty().with_ansi(ansi)).init(),
+        _ => registry.with(fmt::layer().with_ansi(ansi)).init(),
+    }
+}
+
+/// スパンのフィールド
+/// Sentryはトランザクションを送らない場合に最上位のスパン (feed など) のフィールドを捨てるため自前で保持します。
+struct SpanFields(BTreeMap<String, String>);
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+struct SpanFieldsLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanFieldsLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = SpanFields(BTreeMap::new());
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(fields);
+        }
+    }
+}
+
+/// エラーはイベントとして、スパンの名前を stage に、スパンのフィールド (feed, post_id など) をタグに付けて送ります。
+/// 警告と情報はパンくずリストにします。
+fn map_event<S: Subscriber + for<'a> LookupSpan<'a>>(
+    event: &Event,
+    ctx: Context<'_, S>,
+) -> EventMapping {
+    match *event.metadata().level() {
+        Level::ERROR => {
+            let mut sentry_event = event_from_event(event, None::<&Context<'_, S>>);
+            if let Some(scope) = ctx.event_scope(event) {
+                let spans = scope.collect::<Vec<_>>();
+                if let Some(span) = spans.first() {
+                    sentry_event
+                        .tags
+                        .insert("stage".to_string(), span.name().to_string());
+                }
+                // 内側のスパンのフィールドを優先する
+                for span in spans.iter().rev() {
+                    if let Some(fields) = span.extensions().get::<SpanFields>() {
+                        sentry_event.tags.extend(fields.0.clone());
+                    }
+                }
+            }
+            EventMapping::Event(sentry_event)
+        }
+        Level::WARN | Level::INFO => {
+            EventMapping::Breadcrumb(breadcrumb_from_event(event, None::<&Context<'_, S>>))
+        }
+        _ => EventMapping::Ignore,
+    }
+}
+
+/// 設定ファイルの誤り
+#[derive(Debug)]
+pub struct InvalidConfig {
+    pub file: String,
+    /// 位置と誤りの内容
+    pub issues: Vec<(Option<(usize, usize)>, ConfigIssue)>,
+}
+
+impl std::fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} problems found in {}", self.issues.len(), self.file)?;
+        for (position, issue) in &self.issues {
+            match position {
+                Some((line, col)) => write!(f, "\n{}:{}:{}: {}", self.file, line, col, issue)?,
+                None => write!(f, "\n{}: {}", self.file, issue)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+/// 設定ファイルを読み込み、トークンの参照を解決して正規表現やURLなどを検証します。
+/// 誤りがある場合は全ての誤りを位置とともに返します。
 pub fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let path =
-        env::var(FEED_CONFIG_PATH_ENV).expect(&format!("{} must be set", FEED_CONFIG_PATH_ENV));
-    let file = File::open(path)?;
-    let config: Config = serde_yaml::from_reader(file)?;
+    let path = env::var(FEED_CONFIG_PATH_ENV)
+        .unwrap_or_else(|_| panic!("{} must be set", FEED_CONFIG_PATH_ENV));
+    let text = fs::read_to_string(&path)?;
+    let mut config: Config = serde_yaml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let mut issues = config.resolve_secrets();
+    issues.extend(config.validate());
+    if !issues.is_empty() {
+        let paths = YamlPaths::parse(&text);
+        return Err(Box::new(InvalidConfig {
+            issues: issues
+                .into_iter()
+                .map(|issue| (paths.find(&issue.path), issue))
+                .collect(),
+            file: path,
+        }));
+    }
     Ok(config)
 }
 
@@ -26,7 +160,10 @@ pub async fn setup_connection() -> Result<DatabaseConnection, DbErr> {
                 break Ok(db);
             }
             Err(err) => {
-                println!("Failed to connect to database: {}, Retrying in 5 seconds", err);
+                warn!(
+                    error = &err as &dyn std::error::Error,
+                    "Failed to connect to database, Retrying in 5 seconds"
+                );
                 tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
             }
         }
@@ -38,31 +175,171 @@ pub async fn setup_tables() -> Result<(), DbErr> {
     let backend = db.get_database_backend();
     let schema = Schema::new(backend);
     let schema_manager = SchemaManager::new(&db);
+    setup_table(&schema, &schema_manager, PostItem).await?;
+    setup_table(&schema, &schema_manager, FeedInfo).await?;
+    setup_table(&schema, &schema_manager, PostDestination).await?;
+    setup_table(&schema, &schema_manager, FetchLog).await?;
+    setup_unique_destination(&db, &schema_manager).await?;
+    backfill_destinations(&db).await?;
+    Ok(())
+}
+
+/// 投稿先ごとに記録する前に投稿した記事を既定の投稿先の投稿として記録し、編集や取り下げの対象にします。
+async fn backfill_destinations(db: &DatabaseConnection) -> Result<(), DbErr> {
+    use post_destination::Column;
+    let items = sea_query::Query::select()
+        .column(post_item::Column::Id)
+        .expr(sea_query::Expr::val(DEFAULT_DESTINATION))
+        .column(post_item::Column::PostId)
+        .column(post_item::Column::Retracted)
+        .from(PostItem)
+        .and_where(post_item::Column::PostId.is_not_null())
+        // ドライランの記事は投稿されていない
+        .and_where(post_item::Column::PostId.ne(""))
+        .and_where(
+            sea_query::Expr::col(post_item::Column::Id).not_in_subquery(
+                sea_query::Query::select()
+                    .column(Column::ItemId)
+                    .from(PostDestination)
+                    .take(),
+            ),
+        )
+        .take();
+    let insert = sea_query::Query::insert()
+        .into_table(PostDestination)
+        .columns([
+            Column::ItemId,
+            Column::Destination,
+            Column::PostId,
+            Column::Retracted,
+        ])
+        .select_from(items)
+        .map_err(|e| DbErr::Custom(e.to_string()))?
+        .to_owned();
+    let res = db.execute(db.get_database_backend().build(&insert)).await?;
+    if res.rows_affected() > 0 {
+        info!(
+            count = res.rows_affected(),
+            "posted destinations backfilled"
+        );
+    }
+    Ok(())
+}
+
+/// 再試行で同じ投稿先の投稿結果を重複して記録しないよう、記事と投稿先の組を一意にします。
+async fn setup_unique_destination(
+    db: &DatabaseConnection,
+    schema_manager: &SchemaManager<'_>,
+) -> Result<(), DbErr> {
+    use post_destination::Column;
+    // 一意にする前に重複して記録されたものは最初の記録を残す
+    let dedup = sea_query::Query::delete()
+        .from_table(PostDestination)
+        .and_where(
+            sea_query::Expr::col(Column::Id).not_in_subquery(
+                sea_query::Query::select()
+                    .expr(sea_query::Expr::col(Column::Id).min())
+                    .from(PostDestination)
+                    .group_by_columns([Column::ItemId, Column::Destination])
+                    .take(),
+            ),
+        )
+        .to_owned();
+    db.execute(db.get_database_backend().build(&dedup)).await?;
     schema_manager
-        .create_table(
-            schema
-                .create_table_from_entity(PostItem)
+        .create_index(
+            sea_query::Index::create()
                 .if_not_exists()
+                .name("idx-post_destination-item_id-destination")
+                .table(PostDestination)
+                .col(Column::ItemId)
+                .col(Column::Destination)
+                .unique()
                 .take(),
         )
-        .await?;
+        .await
+}
+
+/// テーブルがなければ作成し、既存のテーブルに足りない列とインデックスを追加します。
+async fn setup_table<E: EntityTrait>(
+    schema: &Schema,
+    schema_manager: &SchemaManager<'_>,
+    entity: E,
+) -> Result<(), DbErr> {
     schema_manager
         .create_table(
             schema
-                .create_table_from_entity(FeedInfo)
+                .create_table_from_entity(entity)
                 .if_not_exists()
                 .take(),
         )
         .await?;
-    for mut stmt in schema.create_index_from_entity(PostItem) {
+    for column in E::Column::iter() {
+        if schema_manager
+            .has_column(entity.table_name(), column.to_string())
+            .await?
+        {
+            continue;
+        }
         schema_manager
-            .create_index(stmt.if_not_exists().take())
+            .alter_table(
+                sea_query::Table::alter()
+                    .table(entity)
+                    .add_column(&mut schema.get_column_def::<E>(column))
+                    .take(),
+            )
             .await?;
     }
-    for mut stmt in schema.create_index_from_entity(FeedInfo) {
+    for mut stmt in schema.create_index_from_entity(entity) {
         schema_manager
             .create_index(stmt.if_not_exists().take())
             .await?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_config_reports_positions() {
+        let path = env::temp_dir().join(format!("mastaker-{}.yml", std::process::id()));
+        fs::write(
+            &path,
+            "\
+base_url: https://example.com
+feeds:
+- id: a
+  url: https://example.com/a
+  token: t
+  tag:
+    always: []
+    ignore: ['(']
+    replace: []
+- id: a
+  url: ftp://example.com/b
+  token: t
+",
+        )
+        .unwrap();
+        env::set_var(FEED_CONFIG_PATH_ENV, &path);
+        let result = load_config();
+        fs::remove_file(&path).unwrap();
+        let error = result.unwrap_err();
+        let error = error.downcast_ref::<InvalidConfig>().unwrap();
+        let issues = error
+            .issues
+            .iter()
+            .map(|(position, issue)| (*position, issue.path.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            vec![
+                (Some((8, 14)), "feeds[0].tag.ignore[0]"),
+                (Some((10, 7)), "feeds[1].id"),
+                (Some((11, 8)), "feeds[1].url"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, FixedOffset};
+use minijinja::{Environment, Error, ErrorKind};
+use serde_derive::Serialize;
+
+/// テンプレートが指定されていない場合の投稿の書式
+/// タイトル、リンク、空行、ハッシュタグの順に並べる
+pub const DEFAULT_TEMPLATE: &str = "{% if title %}{{ title }}
+{% endif %}{% for link in links %}{{ link }}
+{% endfor %}{% if tags %}
+{{ tags | join(' ') }}{% endif %}";
+
+/// 本文とCWのテンプレート
+#[derive(Clone, Copy, Debug)]
+pub struct StatusTemplate<'a> {
+    pub text: &'a str,
+    pub spoiler_text: Option<&'a str>,
+}
+
+/// テンプレートから参照できる値
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusContext {
+    /// フィードのID
+    pub id: String,
+    pub feed_title: Option<String>,
+    pub title: Option<String>,
+    /// 最初のリンク
+    pub link: Option<String>,
+    pub links: Vec<String>,
+    /// HTMLタグを除いた概要
+    pub summary: Option<String>,
+    pub author: Option<String>,
+    /// 公開日時 (RFC3339)
+    pub published: Option<String>,
+    /// `#` 付きのハッシュタグ
+    pub tags: Vec<String>,
+}
+
+impl StatusContext {
+    pub fn render(&self, template: &str) -> Result<String, Error> {
+        environment().render_str(template, self)
+    }
+}
+
+/// テンプレートの構文を検証します。
+pub fn validate_template(template: &str) -> Result<(), Error> {
+    environment().template_from_str(template).map(|_| ())
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.add_filter("truncate", truncate);
+    env.add_filter("date", date);
+    env
+}
+
+/// 文字数が `length` を超える場合に末尾を `end` に置き換えます。
+/// 例: `{{ title | truncate(50) }}`, `{{ summary | truncate(100, "...") }}`
+fn truncate(value: String, length: usize, end: Option<String>) -> String {
+    if value.chars().count() <= length {
+        return value;
+    }
+    let end = end.unwrap_or("…".to_string());
+    let keep = length.saturating_sub(end.chars().count());
+    value.chars().take(keep).chain(end.chars()).collect()
+}
+
+/// RFC3339 の日時を指定した書式で出力します。`offset` は UTC からの時差 (時間) です。
+/// 例: `{{ published | date("%Y/%m/%d %H:%M", 9) }}`
+fn date(value: String, format: String, offset: Option<i32>) -> Result<String, Error> {
+    let date = DateTime::parse_from_rfc3339(&value)
+        .map_err(|e| Error::new(ErrorKind::InvalidOperation, e.to_string()))?;
+    let offset = FixedOffset::east_opt(offset.unwrap_or(0) * 3600)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidOperation, "invalid offset"))?;
+    Ok(date.with_timezone(&offset).format(&format).to_string())
+}
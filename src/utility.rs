@@ -1,8 +1,14 @@
 use crate::ext_trait::*;
 use chrono::Duration;
+use feed_rs::{
+    model::{Feed, Link, Text},
+    parser::{Builder, ParseFeedError},
+};
+use reqwest::Url;
+use tracing::debug;
 
 pub async fn sleep(duration: &Duration, reason: &str) {
-    println!("{} sleep {}", reason, duration.to_iso8601());
+    debug!(reason, duration = %duration.to_iso8601(), "sleep");
     #[cfg(feature = "skip_sleep")]
     tokio::time::sleep(
         match duration {
@@ -16,3 +22,104 @@ pub async fn sleep(duration: &Duration, reason: &str) {
     #[cfg(not(feature = "skip_sleep"))]
     tokio::time::sleep(duration.to_std().unwrap()).await;
 }
+
+/// 同じ記事を指すリンクを比較できるように正規化します。
+/// フラグメントと utm_* パラメーター、末尾のスラッシュを除去します。
+pub fn normalize_link(href: &str) -> String {
+    let Ok(mut url) = Url::parse(href.trim()) else {
+        return href.trim().to_string();
+    };
+    url.set_fragment(None);
+    let query = url
+        .query_pairs()
+        .filter(|(k, _)| !k.starts_with("utm_"))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect::<Vec<_>>();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+    let mut link = url.to_string();
+    if link.ends_with('/') {
+        link.pop();
+    }
+    link
+}
+
+/// GUIDがない記事のIDを生成します。
+/// タイトルが修正されても同じ記事と判定できるように、リンクのみから生成します。
+/// リンクが変わるとIDも変わるため、新着の判定ではタイトルと公開日時が一致する登録済みの記事も
+/// 同じ記事とみなします (find_new_entries)。
+pub fn generate_entry_id(links: &[Link], title: &Option<Text>, _uri: Option<&str>) -> String {
+    if let Some(link) = links.first() {
+        normalize_link(&link.href)
+    } else if let Some(title) = title {
+        title.content.clone()
+    } else {
+        String::new()
+    }
+}
+
+/// フィードを解析します。GUIDがない記事には generate_entry_id でIDを付けます。
+pub fn parse_feed(url: &str, content: &[u8]) -> Result<Feed, ParseFeedError> {
+    Builder::new()
+        .base_uri(Some(url))
+        .id_generator(generate_entry_id)
+        .build()
+        .parse(content)
+}
+
+/// フィードを取得して解析します。
+pub async fn fetch_feed(url: &str) -> anyhow::Result<Feed> {
+    let content = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+    Ok(parse_feed(url, content.as_ref())?)
+}
+
+/// anyhow のエラーを tracing のフィールドとして記録できる形にします。
+/// Sentry には例外として送られます。
+pub fn error_value(e: &anyhow::Error) -> &(dyn std::error::Error + 'static) {
+    e.as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_link_strips_utm_parameters() {
+        assert_eq!(
+            normalize_link("https://example.com/a?utm_source=x&id=1&utm_medium=y"),
+            "https://example.com/a?id=1"
+        );
+        assert_eq!(
+            normalize_link("https://example.com/a?utm_source=x&utm_campaign=z"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalize_link("https://example.com/a#section"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_link_strips_trailing_slash() {
+        assert_eq!(
+            normalize_link("https://example.com/a/"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalize_link("https://example.com/"),
+            "https://example.com"
+        );
+        assert_eq!(
+            normalize_link(" https://example.com/a/?utm_source=x "),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_link_keeps_invalid_url() {
+        assert_eq!(normalize_link(" /relative/ "), "/relative/");
+    }
+}
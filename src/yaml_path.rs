@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use yaml_rust2::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+/// YAMLの各項目の位置 (行, 列)
+/// 項目は `feeds[0].tag.ignore[1]` の形式で指定します。
+#[derive(Debug, Default)]
+pub struct YamlPaths(HashMap<String, (usize, usize)>);
+
+impl YamlPaths {
+    /// 構文が正しくない場合は空になります。
+    pub fn parse(text: &str) -> Self {
+        let mut receiver = Receiver::default();
+        if Parser::new_from_str(text)
+            .load(&mut receiver, false)
+            .is_err()
+        {
+            return Self::default();
+        }
+        Self(receiver.paths)
+    }
+
+    /// 項目の位置を返します。見つからない場合は親の項目の位置を返します。
+    pub fn find(&self, path: &str) -> Option<(usize, usize)> {
+        let mut path = path;
+        loop {
+            if let Some(position) = self.0.get(path) {
+                return Some(*position);
+            }
+            path = &path[..path.rfind(['.', '['])?];
+        }
+    }
+}
+
+enum Frame {
+    /// キーを待っている場合は None
+    Mapping(Option<String>),
+    Sequence(usize),
+}
+
+#[derive(Default)]
+struct Receiver {
+    stack: Vec<(String, Frame)>,
+    paths: HashMap<String, (usize, usize)>,
+}
+
+impl Receiver {
+    /// 現在の値の項目を返します。
+    fn value_path(&self) -> Option<String> {
+        match self.stack.last() {
+            None => Some(String::new()),
+            Some((path, Frame::Mapping(Some(key)))) if path.is_empty() => Some(key.clone()),
+            Some((path, Frame::Mapping(Some(key)))) => Some(format!("{}.{}", path, key)),
+            Some((path, Frame::Sequence(i))) => Some(format!("{}[{}]", path, i)),
+            Some((_, Frame::Mapping(None))) => None,
+        }
+    }
+
+    /// 値が終わったので次のキーまたは要素に進みます。
+    fn next(&mut self) {
+        match self.stack.last_mut() {
+            Some((_, Frame::Mapping(key))) => *key = None,
+            Some((_, Frame::Sequence(i))) => *i += 1,
+            None => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for Receiver {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        let position = (mark.line(), mark.col() + 1);
+        match ev {
+            Event::Scalar(value, ..) => match self.value_path() {
+                // キーの位置を記録し、値が文字列であれば値の位置で上書きする
+                None => {
+                    if let Some((path, Frame::Mapping(key))) = self.stack.last_mut() {
+                        let full = if path.is_empty() {
+                            value.clone()
+                        } else {
+                            format!("{}.{}", path, value)
+                        };
+                        self.paths.insert(full, position);
+                        *key = Some(value);
+                    }
+                }
+                Some(path) => {
+                    self.paths.insert(path, position);
+                    self.next();
+                }
+            },
+            Event::Alias(_) => self.next(),
+            Event::MappingStart(..) | Event::SequenceStart(..) => {
+                let path = self.value_path().unwrap_or_default();
+                self.paths.entry(path.clone()).or_insert(position);
+                let frame = match ev {
+                    Event::MappingStart(..) => Frame::Mapping(None),
+                    _ => Frame::Sequence(0),
+                };
+                self.stack.push((path, frame));
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.next();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+base_url: https://example.com
+feeds:
+- id: a
+  tag:
+    ignore:
+    - foo
+    - bar
+- id: b
+  destinations:
+  - id: x
+    token: t
+  - id: y
+    tag:
+      replace: [baz]
+";
+
+    #[test]
+    fn finds_nested_sequences() {
+        let paths = YamlPaths::parse(TEXT);
+        assert_eq!(paths.find("base_url"), Some((1, 11)));
+        assert_eq!(paths.find("feeds[0].id"), Some((3, 7)));
+        assert_eq!(paths.find("feeds[0].tag.ignore[1]"), Some((7, 7)));
+        assert_eq!(paths.find("feeds[1].id"), Some((8, 7)));
+        assert_eq!(paths.find("feeds[1].destinations[0].token"), Some((11, 12)));
+        assert_eq!(
+            paths.find("feeds[1].destinations[1].tag.replace[0]"),
+            Some((14, 17))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_parent() {
+        let paths = YamlPaths::parse(TEXT);
+        // 存在しない項目は最も近い親の位置 (キーの位置) を返す
+        assert_eq!(paths.find("feeds[0].tag.replace[0]"), Some((4, 3)));
+        // 要素の位置は行だけ確認する (列は yaml-rust2 のマッピング開始の位置になる)
+        assert_eq!(
+            paths
+                .find("feeds[1].destinations[0].base_url")
+                .map(|(line, _)| line),
+            Some(10)
+        );
+        assert_eq!(paths.find("unknown"), None);
+    }
+
+    #[test]
+    fn invalid_yaml_is_empty() {
+        let paths = YamlPaths::parse("feeds: [a, b");
+        assert_eq!(paths.find("feeds[0]"), None);
+    }
+}